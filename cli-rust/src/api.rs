@@ -1,10 +1,189 @@
-use derive_more::From;
-use graphql_client;
+use crate::common::try_send_query;
+use crate::context::Context;
+use reqwest::StatusCode;
+use serde_json::{json, Value};
+use std::fmt;
+use std::time::{Duration, Instant};
 
 pub mod ws;
 
-#[derive(Debug, From)]
+#[derive(Debug)]
 pub enum ApiError {
-    Reqwest(reqwest::Error),
-    Graphql(Option<Vec<graphql_client::Error>>),
+    Network(reqwest::Error),
+    Timeout(Duration),
+    Server(StatusCode),
+    RateLimited {
+        retry_after: Option<Duration>,
+    },
+    InvalidResponse(serde_json::Error),
+    Graphql {
+        code: Option<String>,
+        message: String,
+    },
+    EmptyResponse,
+    Unauthorized,
+    NotFound,
+    NoEndpoint,
+}
+
+impl ApiError {
+    /// Classifies the errors of a GraphQL response into the variant that best
+    /// describes them.
+    pub fn from_graphql(errors: Option<Vec<graphql_client::Error>>) -> Self {
+        let errors = match errors {
+            Some(errors) if !errors.is_empty() => errors,
+            _ => return ApiError::EmptyResponse,
+        };
+        if errors.iter().any(is_unauthorized) {
+            ApiError::Unauthorized
+        } else if errors.iter().any(is_not_found) {
+            ApiError::NotFound
+        } else {
+            let messages: Vec<&str> = errors.iter().map(|e| e.message.as_str()).collect();
+            ApiError::Graphql {
+                code: errors.iter().find_map(error_code).map(String::from),
+                message: messages.join("; "),
+            }
+        }
+    }
+
+    /// Whether the request could succeed if sent again unchanged.
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            ApiError::Network(_) | ApiError::Timeout(_) | ApiError::Server(_)
+        )
+    }
+}
+
+/// Sends the cheapest possible query and measures how long the server took
+/// to answer it, no token is needed.
+pub async fn ping(context: &Context) -> Result<Duration, ApiError> {
+    let started_at = Instant::now();
+    let _: Value = try_send_query(context, &json!({ "query": "{ __typename }" })).await?;
+    Ok(started_at.elapsed())
+}
+
+/// The version the server reports in `serverVersion`, or `None` when its
+/// schema has no such field.
+pub async fn server_version(context: &Context) -> Result<Option<String>, ApiError> {
+    let query = json!({ "query": "{ serverVersion }" });
+    match try_send_query::<_, Value>(context, &query).await {
+        Ok(data) => Ok(data["serverVersion"].as_str().map(String::from)),
+        Err(ApiError::Graphql { .. }) => Ok(None),
+        Err(error) => Err(error),
+    }
+}
+
+impl From<reqwest::Error> for ApiError {
+    fn from(error: reqwest::Error) -> Self {
+        ApiError::Network(error)
+    }
+}
+
+impl From<serde_json::Error> for ApiError {
+    fn from(error: serde_json::Error) -> Self {
+        ApiError::InvalidResponse(error)
+    }
+}
+
+fn error_code(error: &graphql_client::Error) -> Option<&str> {
+    error
+        .extensions
+        .as_ref()
+        .and_then(|extensions| extensions.get("code"))
+        .and_then(|code| code.as_str())
+}
+
+fn is_unauthorized(error: &graphql_client::Error) -> bool {
+    error_code(error) == Some("UNAUTHENTICATED") || error.message.starts_with("Access denied")
+}
+
+fn is_not_found(error: &graphql_client::Error) -> bool {
+    error.message.starts_with("Could not find any entity")
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::Network(error) => write!(f, "Could not reach the server: {}", error),
+            ApiError::Timeout(timeout) => {
+                write!(f, "Request timed out after {}s", timeout.as_secs())
+            }
+            ApiError::RateLimited {
+                retry_after: Some(retry_after),
+            } => write!(f, "Rate limited, retry in {}s", retry_after.as_secs()),
+            ApiError::RateLimited { retry_after: None } => {
+                write!(f, "Rate limited, retry later")
+            }
+            ApiError::Server(status) => write!(f, "The server responded with {}", status),
+            ApiError::InvalidResponse(error) => {
+                write!(f, "The server sent an invalid response: {}", error)
+            }
+            ApiError::Graphql { message, .. } => write!(f, "{}", message),
+            ApiError::EmptyResponse => write!(f, "The server returned no data"),
+            ApiError::Unauthorized => {
+                write!(f, "Your session has expired, please run `hashtrack login`")
+            }
+            ApiError::NotFound => write!(f, "The requested item was not found"),
+            ApiError::NoEndpoint => write!(
+                f,
+                "No endpoint configured; set --endpoint, HASHTRACK_ENDPOINT, or `config set endpoint`"
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn errors(response: Value) -> Option<Vec<graphql_client::Error>> {
+        serde_json::from_value(response["errors"].clone()).unwrap()
+    }
+
+    #[test]
+    fn access_denied_is_unauthorized() {
+        let response = json!({ "data": null, "errors": [{
+            "message": "Access denied! You need to be authorized to perform this action!"
+        }] });
+        assert!(matches!(
+            ApiError::from_graphql(errors(response)),
+            ApiError::Unauthorized
+        ));
+    }
+
+    #[test]
+    fn missing_entity_is_not_found() {
+        let response = json!({ "data": null, "errors": [{
+            "message": "Could not find any entity of type \"Track\" matching: {}"
+        }] });
+        assert!(matches!(
+            ApiError::from_graphql(errors(response)),
+            ApiError::NotFound
+        ));
+    }
+
+    #[test]
+    fn other_errors_keep_their_messages_and_code() {
+        let response = json!({ "data": null, "errors": [
+            { "message": "Bad hashtag", "extensions": { "code": "BAD_USER_INPUT" } },
+            { "message": "Try again" }
+        ] });
+        match ApiError::from_graphql(errors(response)) {
+            ApiError::Graphql { code, message } => {
+                assert_eq!(code.as_deref(), Some("BAD_USER_INPUT"));
+                assert_eq!(message, "Bad hashtag; Try again");
+            }
+            error => panic!("unexpected error: {:?}", error),
+        }
+    }
+
+    #[test]
+    fn no_errors_is_an_empty_response() {
+        assert!(matches!(
+            ApiError::from_graphql(None),
+            ApiError::EmptyResponse
+        ));
+    }
 }
@@ -2,8 +2,10 @@ use super::super::context::Context;
 use serde::{Deserialize, Serialize};
 use std::net::TcpStream;
 use websocket::client::sync::Client;
+use websocket::result::WebSocketOtherError;
 use websocket::ClientBuilder;
 use websocket::OwnedMessage;
+use websocket::WebSocketError;
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct WsMessage<T> {
@@ -39,11 +41,12 @@ pub fn get_connection_init_message(context: &Context) -> Vec<u8> {
 }
 
 pub fn is_ack_message(message: WsMessage<String>) -> bool {
-    let ack = String::from("connection_ack");
-    match message {
-        WsMessage { msg_type, .. } if msg_type == ack => true,
-        _ => false,
-    }
+    message.msg_type == "connection_ack"
+}
+
+/// Servers send these on otherwise idle subscriptions to show they're alive.
+pub fn is_keep_alive_message<T>(message: &WsMessage<T>) -> bool {
+    message.msg_type == "ka"
 }
 
 pub fn build_start_message<T: Serialize>(t: T) -> OwnedMessage {
@@ -55,15 +58,33 @@ pub fn build_start_message<T: Serialize>(t: T) -> OwnedMessage {
     OwnedMessage::from(serde_json::to_string(&message).unwrap())
 }
 
-pub fn build_client(endpoint: String) -> Client<TcpStream> {
+/// Asks the server to end the subscription started by `build_start_message`.
+pub fn build_stop_message() -> OwnedMessage {
+    let message: WsMessage<()> = WsMessage {
+        id: Some(1),
+        msg_type: String::from("stop"),
+        payload: None,
+    };
+    OwnedMessage::from(serde_json::to_string(&message).unwrap())
+}
+
+pub fn build_terminate_message() -> OwnedMessage {
+    let message: WsMessage<()> = WsMessage {
+        id: None,
+        msg_type: String::from("connection_terminate"),
+        payload: None,
+    };
+    OwnedMessage::from(serde_json::to_string(&message).unwrap())
+}
+
+pub fn build_client(endpoint: String) -> Result<Client<TcpStream>, WebSocketError> {
     let ws_endpoint = if endpoint.starts_with("ws://") {
         endpoint
     } else {
         get_ws_endpoint(endpoint)
     };
     ClientBuilder::new(&ws_endpoint)
-        .unwrap()
+        .map_err(WebSocketOtherError::from)?
         .add_protocol("graphql-ws")
         .connect_insecure()
-        .unwrap()
 }
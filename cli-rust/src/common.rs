@@ -1,27 +1,274 @@
 use super::api;
 use super::context::Context;
+use ansi_term::Style;
+use chrono::{DateTime, FixedOffset, Utc};
 use graphql_client::Response;
+use reqwest::header::RETRY_AFTER;
+use reqwest::{StatusCode, Url};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::time::{Duration, Instant};
+use tokio::time;
+use tracing::{debug, debug_span, trace, warn, Instrument, Level};
+use uuid::Uuid;
 
+const INITIAL_RETRY_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_RATE_LIMIT_RETRIES: u32 = 3;
+/// Used when a rate limited response has no `Retry-After` in seconds.
+const DEFAULT_RATE_LIMIT_WAIT: Duration = Duration::from_secs(1);
+/// Longer waits are reported instead of blocking the command.
+const MAX_RATE_LIMIT_WAIT: Duration = Duration::from_secs(60);
+
+/// Sends a query, retrying up to `context.retries` times on connection
+/// errors and 5xx responses. Use `try_send_mutation` for anything with side
+/// effects.
 pub async fn try_send_query<T: Serialize + ?Sized, R: for<'a> Deserialize<'a>>(
     context: &Context,
     json: &T,
 ) -> Result<R, api::ApiError> {
-    let res = build_base_request(context)
+    let mut backoff = INITIAL_RETRY_BACKOFF;
+    let mut attempt = 0;
+    loop {
+        let span = debug_span!("query", attempt);
+        match send_rate_limited(context, json).instrument(span).await {
+            Err(error) if error.is_transient() && attempt < context.retries => {
+                attempt += 1;
+                warn!(%error, ?backoff, attempt, "request failed, retrying");
+                time::delay_for(backoff).await;
+                backoff *= 2;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Sends a mutation exactly once, retrying could apply it twice.
+pub async fn try_send_mutation<T: Serialize + ?Sized, R: for<'a> Deserialize<'a>>(
+    context: &Context,
+    json: &T,
+) -> Result<R, api::ApiError> {
+    send_rate_limited(context, json)
+        .instrument(debug_span!("mutation"))
+        .await
+}
+
+/// Sends a request, waiting out up to `MAX_RATE_LIMIT_RETRIES` rate limits.
+/// The server rejects rate limited requests without running them, so this is
+/// safe for mutations too.
+async fn send_rate_limited<T: Serialize + ?Sized, R: for<'a> Deserialize<'a>>(
+    context: &Context,
+    json: &T,
+) -> Result<R, api::ApiError> {
+    let mut attempt = 0;
+    loop {
+        match send_request(context, json).await {
+            Err(api::ApiError::RateLimited { retry_after })
+                if context.retry
+                    && attempt < MAX_RATE_LIMIT_RETRIES
+                    && retry_after.unwrap_or(DEFAULT_RATE_LIMIT_WAIT) <= MAX_RATE_LIMIT_WAIT =>
+            {
+                attempt += 1;
+                let wait = retry_after.unwrap_or(DEFAULT_RATE_LIMIT_WAIT);
+                warn!(?wait, attempt, "rate limited, retrying");
+                time::delay_for(wait).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Sends a request to the first endpoint that can be reached, moving on to
+/// the next one on connection errors and timeouts.
+async fn send_request<T: Serialize + ?Sized, R: for<'a> Deserialize<'a>>(
+    context: &Context,
+    json: &T,
+) -> Result<R, api::ApiError> {
+    if tracing::enabled!(Level::DEBUG) {
+        log_request(json);
+    }
+    let mut endpoints = context.endpoints.iter().peekable();
+    while let Some(endpoint) = endpoints.next() {
+        match send_to_endpoint(context, endpoint, json).await {
+            Err(error @ api::ApiError::Network(_)) | Err(error @ api::ApiError::Timeout(_))
+                if endpoints.peek().is_some() =>
+            {
+                warn!(%endpoint, %error, "endpoint failed, trying the next one");
+            }
+            result => {
+                if result.is_ok() {
+                    debug!(%endpoint, "served by");
+                }
+                return result;
+            }
+        }
+    }
+    Err(api::ApiError::NoEndpoint)
+}
+
+async fn send_to_endpoint<T: Serialize + ?Sized, R: for<'a> Deserialize<'a>>(
+    context: &Context,
+    endpoint: &Url,
+    json: &T,
+) -> Result<R, api::ApiError> {
+    let started_at = Instant::now();
+    let to_api_error = |error: reqwest::Error| {
+        if error.is_timeout() {
+            api::ApiError::Timeout(context.timeout)
+        } else {
+            api::ApiError::from(error)
+        }
+    };
+    // Lets operators find the request in the server logs
+    let request_id = Uuid::new_v4().to_string();
+    let res = build_base_request(context, endpoint)
+        .header("X-Request-Id", &request_id)
         .json(json)
         .send()
-        .await?
-        .json::<Response<R>>()
-        .await?;
-    let errors = res.errors;
-    let data = res.data.ok_or_else(|| api::ApiError::Graphql(errors))?;
-    Ok(data)
+        .await
+        .map_err(to_api_error)?;
+    let status = res.status();
+    let retry_after = res
+        .headers()
+        .get(RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse().ok())
+        .map(Duration::from_secs);
+    let body = res.text().await.map_err(to_api_error)?;
+    trace!(%status, %request_id, elapsed = ?started_at.elapsed(), "response");
+    if tracing::enabled!(Level::DEBUG) {
+        debug!(body = %redact_tokens(&body), %request_id, "response body");
+    }
+    if status == StatusCode::UNAUTHORIZED {
+        return Err(api::ApiError::Unauthorized);
+    }
+    if status == StatusCode::TOO_MANY_REQUESTS {
+        return Err(api::ApiError::RateLimited { retry_after });
+    }
+    if status.is_server_error() {
+        return Err(api::ApiError::Server(status));
+    }
+    let res: Response<R> = serde_json::from_str(&body)?;
+    match res.data {
+        // Errors next to data only mean some fields failed, the rest is usable
+        Some(data) => {
+            for error in res.errors.iter().flatten() {
+                warn!(message = %error.message, "partial response");
+            }
+            Ok(data)
+        }
+        None => Err(api::ApiError::from_graphql(res.errors)),
+    }
+}
+
+fn log_request<T: Serialize + ?Sized>(json: &T) {
+    let mut body = match serde_json::to_value(json) {
+        Ok(body) => body,
+        Err(_) => return,
+    };
+    if let Some(query) = body["query"].as_str() {
+        debug!(query = query.trim(), "request");
+    }
+    if let Some(variables) = body["variables"].as_object_mut() {
+        if variables.contains_key("password") {
+            variables.insert(String::from("password"), Value::from("********"));
+        }
+        let variables = Value::from(variables.clone());
+        debug!(%variables, "request variables");
+    }
+}
+
+/// Masks the `token` fields of a response body, like the one of the session
+/// created when logging in.
+fn redact_tokens(body: &str) -> String {
+    match serde_json::from_str::<Value>(body) {
+        Ok(mut body) => {
+            mask_field(&mut body, "token");
+            body.to_string()
+        }
+        Err(_) => body.to_string(),
+    }
+}
+
+fn mask_field(value: &mut Value, name: &str) {
+    match value {
+        Value::Object(fields) => fields.iter_mut().for_each(|(key, field)| {
+            if key == name && !field.is_null() {
+                *field = Value::from("********");
+            } else {
+                mask_field(field, name);
+            }
+        }),
+        Value::Array(items) => items.iter_mut().for_each(|item| mask_field(item, name)),
+        _ => (),
+    }
 }
 
-fn build_base_request(context: &Context) -> reqwest::RequestBuilder {
-    let builder = reqwest::Client::new().post(&context.endpoint);
+fn build_base_request(context: &Context, endpoint: &Url) -> reqwest::RequestBuilder {
+    let builder = context.client.post(endpoint.clone());
     match context.token() {
         Some(token) => builder.header("Authorization", token),
         None => builder,
     }
 }
+
+pub fn paint(color: bool, style: Style, text: &str) -> String {
+    if color {
+        style.paint(text).to_string()
+    } else {
+        text.to_string()
+    }
+}
+
+/// Describes how long ago `time` was, e.g. "5m ago". Times in the future
+/// (clock skew between client and server) are reported as "just now".
+pub fn format_relative(time: &DateTime<FixedOffset>) -> String {
+    let elapsed = Utc::now().signed_duration_since(*time);
+    if elapsed.num_minutes() < 1 {
+        String::from("just now")
+    } else if elapsed.num_hours() < 1 {
+        format!("{}m ago", elapsed.num_minutes())
+    } else if elapsed.num_days() < 1 {
+        format!("{}h ago", elapsed.num_hours())
+    } else {
+        format!("{}d ago", elapsed.num_days())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use serde_json::json;
+    use std::sync::atomic::Ordering;
+
+    #[tokio::test]
+    async fn queries_are_retried_until_the_server_recovers() {
+        let directory = tempfile::tempdir().unwrap();
+        let (endpoint, served) = testing::serve(|index, _| match index {
+            0 | 1 => (500, String::new()),
+            _ => (
+                200,
+                json!({ "data": { "__typename": "Query" } }).to_string(),
+            ),
+        });
+        let context = testing::context(&endpoint, directory.path());
+        let result: Result<Value, _> =
+            try_send_query(&context, &json!({ "query": "{ __typename }" })).await;
+        assert_eq!(result.unwrap()["__typename"], "Query");
+        assert_eq!(served.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn graphql_errors_are_not_retried() {
+        let directory = tempfile::tempdir().unwrap();
+        let (endpoint, served) = testing::serve(|_, _| {
+            let body = json!({ "data": null, "errors": [{ "message": "Cannot query field" }] });
+            (200, body.to_string())
+        });
+        let context = testing::context(&endpoint, directory.path());
+        let result: Result<Value, _> =
+            try_send_query(&context, &json!({ "query": "{ unknown }" })).await;
+        assert!(matches!(result, Err(api::ApiError::Graphql { .. })));
+        assert_eq!(served.load(Ordering::SeqCst), 1);
+    }
+}
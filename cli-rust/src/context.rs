@@ -1,26 +1,71 @@
-use localconfig::Config;
+use localconfig::{Config, Profile};
+use reqwest::Url;
 use std::env;
+use std::fmt;
 use std::io;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 pub mod localconfig;
 
 const DEFAULT_ENDPOINT: &str = "https://hashtrack.herokuapp.com/graphql";
+const DEFAULT_PROFILE: &str = "default";
+const DEFAULT_TIMEOUT_SECS: u64 = 30;
+const DEFAULT_RETRIES: u32 = 3;
+const DEFAULT_USER_AGENT: &str = concat!("hashtrack-cli/", env!("CARGO_PKG_VERSION"));
+/// Tweet text is wrapped at this width unless told otherwise.
+const DEFAULT_WRAP_WIDTH: usize = 60;
 
 pub struct Context {
-    pub endpoint: String,
+    /// Tried in order until one can be reached, the first one is the primary.
+    pub endpoints: Vec<Url>,
+    pub profile: String,
+    pub json: bool,
+    pub color: bool,
+    pub absolute_time: bool,
+    pub plain: bool,
+    pub format: Option<String>,
+    pub compact: bool,
+    pub verbose: u8,
+    pub timeout: Duration,
+    pub retries: u32,
+    pub retry: bool,
+    pub dry_run: bool,
+    pub quiet: bool,
+    pub wrap: usize,
+    pub pager: bool,
+    pub client: reqwest::Client,
+    endpoint_override: bool,
+    endpoint_error: Option<String>,
+    env_token: Option<String>,
     config: Config,
 }
 
 #[derive(Debug)]
 pub struct ContextError {
     message: String,
+    usage: bool,
+}
+
+impl ContextError {
+    /// Whether the error comes from a bad flag or setting rather than the
+    /// environment, which warrants the usage exit code.
+    pub fn is_usage(&self) -> bool {
+        self.usage
+    }
+}
+
+impl fmt::Display for ContextError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
 }
 
 impl From<io::Error> for ContextError {
     fn from(error: io::Error) -> Self {
         ContextError {
             message: error.to_string(),
+            usage: false,
         }
     }
 }
@@ -29,46 +74,350 @@ impl From<env::VarError> for ContextError {
     fn from(error: env::VarError) -> Self {
         ContextError {
             message: error.to_string(),
+            usage: false,
         }
     }
 }
 
+impl From<reqwest::Error> for ContextError {
+    fn from(error: reqwest::Error) -> Self {
+        ContextError {
+            message: error.to_string(),
+            usage: false,
+        }
+    }
+}
+
+/// What a `Context` is built from. Flags, environment variables and files
+/// like `.env` are up to the caller, the stored profile fills in whatever is
+/// left unset.
+pub struct Settings {
+    /// Only written once something is saved, it doesn't have to exist.
+    pub config_path: PathBuf,
+    /// Read instead of `config_path` as long as that one doesn't exist.
+    pub legacy_config_path: Option<PathBuf>,
+    pub profile: Option<String>,
+    /// Endpoints or comma separated lists of them, the profile's endpoint is
+    /// used when empty.
+    pub endpoints: Vec<String>,
+    /// Used instead of the stored token without ever being saved.
+    pub token: Option<String>,
+    pub json: bool,
+    /// Whether to color the output, resolved by the caller from its flags,
+    /// `stored_color` and the terminal.
+    pub color: bool,
+    pub absolute_time: bool,
+    pub plain: bool,
+    pub format: Option<String>,
+    pub compact: bool,
+    pub verbose: u8,
+    /// Seconds to wait for requests, the profile's timeout is used when unset.
+    pub timeout: Option<u64>,
+    pub retries: u32,
+    pub retry: bool,
+    pub dry_run: bool,
+    pub quiet: bool,
+    pub wrap: usize,
+    pub pager: bool,
+    pub user_agent: Option<String>,
+    pub proxy: Option<String>,
+    pub compression: bool,
+    pub insecure: bool,
+}
+
+impl Settings {
+    /// The settings of the CLI without any flags, reading the config at
+    /// `config_path`.
+    pub fn new(config_path: impl Into<PathBuf>) -> Self {
+        Settings {
+            config_path: config_path.into(),
+            legacy_config_path: None,
+            profile: None,
+            endpoints: Vec::new(),
+            token: None,
+            json: false,
+            color: false,
+            absolute_time: false,
+            plain: false,
+            format: None,
+            compact: false,
+            verbose: 0,
+            timeout: None,
+            retries: DEFAULT_RETRIES,
+            retry: true,
+            dry_run: false,
+            quiet: false,
+            wrap: DEFAULT_WRAP_WIDTH,
+            pager: true,
+            user_agent: None,
+            proxy: None,
+            compression: true,
+            insecure: false,
+        }
+    }
+
+    /// The color setting stored in the selected profile, `auto`, `always`
+    /// or `never`.
+    pub fn stored_color(&self) -> Option<String> {
+        let profile = self.profile.as_deref().unwrap_or(DEFAULT_PROFILE);
+        Config::load(
+            &self.config_path,
+            self.legacy_config_path.as_ref(),
+            DEFAULT_PROFILE,
+        )
+        .profile(profile)?
+        .color
+        .clone()
+    }
+}
+
 impl Context {
-    pub fn new(
-        config_opt: Option<String>,
-        endpoint_opt: Option<String>,
-    ) -> Result<Self, ContextError> {
-        let mut config_path = PathBuf::new();
-        match config_opt {
-            Some(path) => {
-                config_path.push(path);
+    pub fn new(settings: Settings) -> Result<Self, ContextError> {
+        let config = Config::load(
+            &settings.config_path,
+            settings.legacy_config_path.as_ref(),
+            DEFAULT_PROFILE,
+        );
+        let profile = settings
+            .profile
+            .clone()
+            .unwrap_or_else(|| DEFAULT_PROFILE.to_string());
+        check_profile_name(&profile).map_err(|message| ContextError {
+            message,
+            usage: true,
+        })?;
+        let stored = config.profile(&profile);
+        let endpoints = if !settings.endpoints.is_empty() {
+            parse_endpoints(&settings.endpoints.join(",")).map_err(|message| ContextError {
+                message,
+                usage: true,
+            })?
+        } else {
+            Vec::new()
+        };
+        let timeout = settings
+            .timeout
+            .or_else(|| stored.and_then(|stored| stored.timeout))
+            .unwrap_or(DEFAULT_TIMEOUT_SECS);
+        let format = settings
+            .format
+            .clone()
+            .or_else(|| stored.and_then(|stored| stored.format.clone()));
+        let timeout = Duration::from_secs(timeout);
+        let client = build_client(&settings, timeout)?;
+        let mut context = Context {
+            endpoint_override: !settings.endpoints.is_empty(),
+            endpoint_error: None,
+            endpoints,
+            profile,
+            json: settings.json,
+            color: settings.color && !settings.plain,
+            absolute_time: settings.absolute_time,
+            plain: settings.plain,
+            format,
+            compact: settings.compact,
+            verbose: settings.verbose,
+            timeout,
+            retries: settings.retries,
+            retry: settings.retry,
+            dry_run: settings.dry_run,
+            quiet: settings.quiet,
+            wrap: settings.wrap,
+            pager: settings.pager,
+            client,
+            env_token: settings.token,
+            config,
+        };
+        context.resolve_stored_endpoints();
+        Ok(context)
+    }
+
+    /// Uses the endpoints of the stored profile unless they were given in
+    /// the settings. An invalid one only fails the commands that need it, so
+    /// `config set endpoint` can still repair it.
+    fn resolve_stored_endpoints(&mut self) {
+        if self.endpoint_override {
+            return;
+        }
+        let stored = self
+            .config
+            .profile(&self.profile)
+            .and_then(|profile| profile.endpoint.as_deref())
+            .unwrap_or(DEFAULT_ENDPOINT);
+        match parse_endpoints(stored) {
+            Ok(endpoints) => {
+                self.endpoints = endpoints;
+                self.endpoint_error = None;
             }
-            None => {
-                config_path.push(
-                    &env::var("HOME")
-                        .expect("Either -e flag or HOME environment variable should be set"),
-                );
-                config_path.push(".hashtrack.config");
+            Err(error) => {
+                self.endpoints = Vec::new();
+                self.endpoint_error = Some(error);
             }
-        };
-        let config = Config::load(&config_path)?;
-        let endpoint = endpoint_opt.unwrap_or(
-            config
-                .contents
-                .endpoint
-                .as_deref()
-                .unwrap_or(DEFAULT_ENDPOINT)
-                .to_string(),
-        );
-        Ok(Context { endpoint, config })
+        }
+    }
+
+    /// Why the endpoint stored in the profile can't be used, if it is the
+    /// one in effect.
+    pub fn endpoint_error(&self) -> Option<&str> {
+        self.endpoint_error.as_deref()
     }
 
     pub fn set_token(&mut self, token: Option<String>) -> io::Result<()> {
-        self.config.contents.token = token;
+        self.config.profile_mut(&self.profile).token = token;
         self.config.save()
     }
 
+    /// The token from HASHTRACK_TOKEN if set, otherwise the stored one.
     pub fn token(&self) -> Option<&str> {
-        self.config.contents.token.as_deref()
+        self.env_token.as_deref().or_else(|| {
+            self.config
+                .profile(&self.profile)
+                .and_then(|profile| profile.token.as_deref())
+        })
+    }
+
+    /// Uses `token` for the following requests without storing it, returning
+    /// the token it replaces.
+    pub fn override_token(&mut self, token: Option<String>) -> Option<String> {
+        std::mem::replace(&mut self.env_token, token)
+    }
+
+    /// Whether the token comes from HASHTRACK_TOKEN, which logging in
+    /// can't replace.
+    pub fn token_from_env(&self) -> bool {
+        self.env_token.is_some()
+    }
+
+    pub fn last_seen_tweet_id(&self) -> Option<&str> {
+        self.config
+            .profile(&self.profile)
+            .and_then(|profile| profile.last_seen_tweet_id.as_deref())
+    }
+
+    pub fn set_last_seen_tweet_id(&mut self, id: String) -> io::Result<()> {
+        self.config.profile_mut(&self.profile).last_seen_tweet_id = Some(id);
+        self.config.save()
+    }
+
+    pub fn config_path(&self) -> &Path {
+        &self.config.path
+    }
+
+    /// The endpoint used for subscriptions and shown to the user, empty when
+    /// none is configured.
+    pub fn endpoint(&self) -> &str {
+        self.endpoints.first().map_or("", Url::as_str)
+    }
+
+    /// Where the last `tracks` result of the current profile is kept.
+    pub fn tracks_cache_path(&self) -> PathBuf {
+        self.config
+            .path
+            .with_file_name(format!("tracks-{}.json", self.profile))
+    }
+
+    /// Set when the config file can be read by other users, for the caller
+    /// to warn about.
+    pub fn config_warning(&self) -> Option<&str> {
+        self.config.permissions_warning.as_deref()
+    }
+
+    /// Problems with the config file and the endpoint it resolves to.
+    pub fn config_problems(&self) -> Vec<String> {
+        // An invalid stored endpoint is already among the config's problems
+        let mut problems = self.config.problems();
+        if self.endpoints.is_empty() && self.endpoint_error.is_none() {
+            problems.push(String::from("resolved endpoint is empty"));
+        }
+        problems
+    }
+
+    /// The settings stored for the current profile, before flags and
+    /// environment variables are applied.
+    pub fn stored_profile(&self) -> Option<&Profile> {
+        self.config.profile(&self.profile)
+    }
+
+    pub fn update_stored_profile(&mut self, update: impl FnOnce(&mut Profile)) -> io::Result<()> {
+        update(self.config.profile_mut(&self.profile));
+        self.config.save()?;
+        self.resolve_stored_endpoints();
+        Ok(())
+    }
+
+    pub fn profile_names(&self) -> Vec<&str> {
+        self.config.profile_names()
+    }
+}
+
+/// Without a proxy reqwest picks up HTTP_PROXY and HTTPS_PROXY by itself.
+fn build_client(settings: &Settings, timeout: Duration) -> Result<reqwest::Client, ContextError> {
+    let user_agent = settings.user_agent.as_deref().unwrap_or(DEFAULT_USER_AGENT);
+    let mut builder = reqwest::Client::builder()
+        .timeout(timeout)
+        .user_agent(user_agent);
+    // Responses are gzip or brotli compressed when the server supports it
+    if !settings.compression {
+        builder = builder.no_gzip().no_brotli();
+    }
+    if let Some(proxy) = &settings.proxy {
+        let proxy = Url::parse(proxy)
+            .map_err(|error| error.to_string())
+            .and_then(|url| reqwest::Proxy::all(url).map_err(|error| error.to_string()))
+            .map_err(|error| ContextError {
+                message: format!("Invalid --proxy \"{}\": {}", proxy, error),
+                usage: true,
+            })?;
+        builder = builder.proxy(proxy);
+    }
+    if settings.insecure {
+        builder = builder.danger_accept_invalid_certs(true);
+    }
+    Ok(builder.build()?)
+}
+
+/// Profile names end up in file names like the tracks cache, so they are
+/// limited to characters that can't leave the config directory.
+fn check_profile_name(name: &str) -> Result<(), String> {
+    let valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if valid {
+        Ok(())
+    } else {
+        Err(format!(
+            "Invalid profile \"{}\": only letters, digits, '-' and '_' are allowed",
+            name
+        ))
+    }
+}
+
+fn parse_endpoints(endpoints: &str) -> Result<Vec<Url>, String> {
+    localconfig::parse_endpoints(endpoints).map_err(|error| format!("Invalid {}", error))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn invalid_proxy_is_a_usage_error() {
+        let directory = tempfile::tempdir().unwrap();
+        let settings = Settings {
+            proxy: Some(String::from("::")),
+            ..Settings::new(directory.path().join("config.toml"))
+        };
+        let error = Context::new(settings).err().unwrap();
+        assert!(error.is_usage());
+        assert!(error.to_string().contains("--proxy"));
+    }
+
+    #[test]
+    fn profile_names_cannot_leave_the_config_directory() {
+        assert!(check_profile_name("work_2-eu").is_ok());
+        assert!(check_profile_name("../../x").is_err());
+        assert!(check_profile_name("a/b").is_err());
+        assert!(check_profile_name("").is_err());
     }
 }
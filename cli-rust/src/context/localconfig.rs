@@ -1,51 +1,348 @@
+use reqwest::Url;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::ffi::{OsStr, OsString};
+use std::fs;
 use std::fs::File;
 use std::io;
-use std::path::PathBuf;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
 
-#[derive(Serialize, Deserialize)]
-pub struct Contents {
+#[derive(Serialize, Deserialize, Default)]
+pub struct Profile {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub token: Option<String>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
     pub endpoint: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_seen_tweet_id: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub color: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<u64>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub format: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct Contents {
+    #[serde(default)]
+    pub profiles: BTreeMap<String, Profile>,
+
+    // Older versions stored a single token and endpoint at the top level
+    #[serde(default, skip_serializing)]
+    token: Option<String>,
+
+    #[serde(default, skip_serializing)]
+    endpoint: Option<String>,
 }
 
+/// Session tokens are 48 random bytes encoded as base64.
+const TOKEN_LENGTH: usize = 64;
+
 pub struct Config {
     pub path: PathBuf,
     pub contents: Contents,
+    /// Why the existing file couldn't be read. Saving fails as long as it is
+    /// set, so the file is never replaced with empty defaults.
+    pub load_error: Option<String>,
+    /// Set when the file can be read by other users, it holds tokens.
+    pub permissions_warning: Option<String>,
+}
+
+impl Contents {
+    fn migrate(&mut self, default_profile: &str) {
+        if self.token.is_none() && self.endpoint.is_none() {
+            return;
+        }
+        let legacy = Profile {
+            token: self.token.take(),
+            endpoint: self.endpoint.take(),
+            ..Profile::default()
+        };
+        self.profiles
+            .entry(default_profile.to_string())
+            .or_insert(legacy);
+    }
 }
 
 impl Config {
-    fn read(path: &PathBuf) -> io::Result<Contents> {
-        let file = File::open(path)?;
-        let result = serde_json::from_reader(file)?;
-        Ok(result)
+    fn is_toml(path: &Path) -> bool {
+        path.extension() == Some(OsStr::new("toml"))
     }
 
-    pub fn load(path: &PathBuf) -> io::Result<Self> {
-        match Config::read(path) {
-            Ok(contents) => Ok(Config {
-                path: path.clone(),
-                contents,
-            }),
-            Err(_) => {
-                let mut config = Config {
+    /// Reads the file at `path` along with a warning about its permissions.
+    fn read(path: &PathBuf) -> io::Result<(Contents, Option<String>)> {
+        let mut file = File::open(path)?;
+        let warning = check_permissions(&file, path);
+        let contents = if Config::is_toml(path) {
+            let mut text = String::new();
+            file.read_to_string(&mut text)?;
+            toml::from_str(&text)
+                .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?
+        } else {
+            serde_json::from_reader(file)?
+        };
+        Ok((contents, warning))
+    }
+
+    /// Loads the config at `path`, importing the contents of `legacy_path`
+    /// when the former doesn't exist yet.
+    pub fn load(path: &PathBuf, legacy_path: Option<&PathBuf>, default_profile: &str) -> Self {
+        let contents = Config::read(path).or_else(|error| match legacy_path {
+            Some(legacy_path) if !path.exists() => Config::read(legacy_path),
+            _ => Err(error),
+        });
+        match contents {
+            Ok((mut contents, permissions_warning)) => {
+                contents.migrate(default_profile);
+                Config {
                     path: path.clone(),
-                    contents: Contents {
-                        token: None,
-                        endpoint: None,
-                    },
-                };
-                config.save()?;
-                return Ok(config);
+                    contents,
+                    load_error: None,
+                    permissions_warning,
+                }
             }
+            Err(error) if path.exists() => Config {
+                path: path.clone(),
+                contents: Contents::default(),
+                load_error: Some(error.to_string()),
+                permissions_warning: None,
+            },
+            // Nothing is written until there is something to save
+            Err(_) => Config {
+                path: path.clone(),
+                contents: Contents::default(),
+                load_error: None,
+                permissions_warning: None,
+            },
         }
     }
 
+    /// Writes the config with `write_atomically`, so an interrupted save never
+    /// leaves it truncated.
     pub fn save(&mut self) -> io::Result<()> {
-        let file = File::create(&self.path)?;
-        Ok(serde_json::to_writer(file, &self.contents)?)
+        if let Some(error) = &self.load_error {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Not overwriting {}, it could not be read ({}), fix or remove it first",
+                    self.path.display(),
+                    error
+                ),
+            ));
+        }
+        let text = if Config::is_toml(&self.path) {
+            toml::to_string(&self.contents)
+                .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?
+        } else {
+            serde_json::to_string(&self.contents)?
+        };
+        write_atomically(&self.path, text.as_bytes())
+    }
+
+    pub fn profile(&self, name: &str) -> Option<&Profile> {
+        self.contents.profiles.get(name)
+    }
+
+    pub fn profile_mut(&mut self, name: &str) -> &mut Profile {
+        self.contents.profiles.entry(name.to_string()).or_default()
+    }
+
+    pub fn profile_names(&self) -> Vec<&str> {
+        self.contents.profiles.keys().map(String::as_str).collect()
+    }
+
+    /// Describes everything that looks wrong with the file, one line each.
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if let Some(error) = &self.load_error {
+            problems.push(format!("the file could not be read: {}", error));
+        }
+        for (name, profile) in &self.contents.profiles {
+            if let Some(Err(error)) = profile.endpoint.as_deref().map(parse_endpoints) {
+                problems.push(format!("profile \"{}\": {}", name, error));
+            }
+            if let Some(Err(error)) = profile.token.as_deref().map(check_token) {
+                problems.push(format!("profile \"{}\": {}", name, error));
+            }
+        }
+        problems
+    }
+}
+
+/// Parses a comma separated list of endpoints, dropping empty entries. Each
+/// one has to be an http(s) URL with a host, where a missing scheme defaults
+/// to https and trailing slashes are dropped.
+pub fn parse_endpoints(endpoints: &str) -> Result<Vec<Url>, String> {
+    endpoints
+        .split(',')
+        .map(str::trim)
+        .filter(|endpoint| !endpoint.is_empty())
+        .map(canonicalize_endpoint)
+        .collect()
+}
+
+fn canonicalize_endpoint(endpoint: &str) -> Result<Url, String> {
+    let mut url = if endpoint.contains("://") {
+        parse_endpoint(endpoint)?
+    } else {
+        parse_endpoint(&format!("https://{}", endpoint))?
+    };
+    let path = url.path().trim_end_matches('/').to_string();
+    url.set_path(&path);
+    Ok(url)
+}
+
+fn parse_endpoint(endpoint: &str) -> Result<Url, String> {
+    let url = Url::parse(endpoint)
+        .map_err(|error| format!("endpoint \"{}\" is not a valid URL: {}", endpoint, error))?;
+    if url.scheme() != "http" && url.scheme() != "https" {
+        return Err(format!(
+            "endpoint \"{}\" should start with http:// or https://",
+            endpoint
+        ));
+    }
+    if url.host_str().is_none_or(str::is_empty) {
+        return Err(format!("endpoint \"{}\" has no host", endpoint));
+    }
+    Ok(url)
+}
+
+fn check_token(token: &str) -> Result<(), String> {
+    let is_base64 = token
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '/' | '='));
+    if token.len() != TOKEN_LENGTH || !is_base64 {
+        return Err(String::from(
+            "the token looks truncated or corrupted, run `hashtrack login` again",
+        ));
+    }
+    Ok(())
+}
+
+/// Writes `contents` to a temporary file next to `path` and renames that over
+/// it, so `path` either keeps its previous contents or has all of the new
+/// ones. Only the owner can read the file on unix.
+pub fn write_atomically(path: &Path, contents: &[u8]) -> io::Result<()> {
+    if let Some(directory) = path.parent() {
+        fs::create_dir_all(directory)?;
+    }
+    let temporary_path = temporary_path(path);
+    let written =
+        write_synced(&temporary_path, contents).and_then(|()| fs::rename(&temporary_path, path));
+    if let Err(error) = written {
+        // The previous file is still intact, only the partial copy goes
+        fs::remove_file(&temporary_path).ok();
+        return Err(error);
+    }
+    sync_parent_directory(path);
+    Ok(())
+}
+
+fn temporary_path(path: &Path) -> PathBuf {
+    let mut name = OsString::from(".");
+    name.push(path.file_name().unwrap_or_else(|| OsStr::new("config")));
+    name.push(".tmp");
+    path.with_file_name(name)
+}
+
+fn write_synced(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut file = File::create(path)?;
+    restrict_permissions(path)?;
+    file.write_all(contents)?;
+    file.sync_all()
+}
+
+// Makes the rename itself survive a crash, it's only best effort since the
+// new config is already in place
+#[cfg(unix)]
+fn sync_parent_directory(path: &Path) {
+    let directory = match path.parent() {
+        Some(directory) if !directory.as_os_str().is_empty() => directory,
+        _ => Path::new("."),
+    };
+    if let Ok(directory) = File::open(directory) {
+        directory.sync_all().ok();
+    }
+}
+
+#[cfg(not(unix))]
+fn sync_parent_directory(_path: &Path) {}
+
+// The config holds session tokens, so only its owner should be able to read it
+#[cfg(unix)]
+fn restrict_permissions(path: &Path) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))
+}
+
+#[cfg(not(unix))]
+fn restrict_permissions(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn check_permissions(file: &File, path: &Path) -> Option<String> {
+    use std::os::unix::fs::PermissionsExt;
+    let mode = file.metadata().ok()?.permissions().mode();
+    if mode & 0o077 == 0 {
+        return None;
+    }
+    Some(format!(
+        "{} is accessible by other users, run `chmod 600` on it",
+        path.display()
+    ))
+}
+
+#[cfg(not(unix))]
+fn check_permissions(_file: &File, _path: &Path) -> Option<String> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load_from(directory: &Path) -> Config {
+        Config::load(&directory.join("config.toml"), None, "default")
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn saved_config_is_only_readable_by_its_owner() {
+        use std::os::unix::fs::PermissionsExt;
+        let directory = tempfile::tempdir().unwrap();
+        let mut config = load_from(directory.path());
+        config.profile_mut("default").token = Some(String::from("token"));
+        config.save().unwrap();
+        let mode = fs::metadata(&config.path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[test]
+    fn failed_save_leaves_the_previous_config_intact() {
+        let directory = tempfile::tempdir().unwrap();
+        let mut config = load_from(directory.path());
+        config.profile_mut("default").token = Some(String::from("old"));
+        config.save().unwrap();
+        let previous = fs::read_to_string(&config.path).unwrap();
+
+        // A directory in place of the temporary file makes the write fail
+        fs::create_dir(temporary_path(&config.path)).unwrap();
+        config.profile_mut("default").token = Some(String::from("new"));
+        assert!(config.save().is_err());
+
+        assert_eq!(fs::read_to_string(&config.path).unwrap(), previous);
+        let reloaded = load_from(directory.path());
+        assert_eq!(
+            reloaded.profile("default").unwrap().token.as_deref(),
+            Some("old")
+        );
     }
 }
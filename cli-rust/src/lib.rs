@@ -0,0 +1,13 @@
+//! Client for the hashtrack GraphQL API, the `hashtrack` binary is a thin
+//! command line interface over these modules.
+
+pub mod api;
+pub mod common;
+pub mod context;
+pub mod session;
+pub mod track;
+pub mod tweet;
+pub mod user;
+
+#[cfg(test)]
+mod testing;
@@ -1,40 +1,431 @@
-use crate::opts::{HashtrackCommand, HashtrackOpt};
-use context::Context;
+use ansi_term::Style;
+use chrono::{DateTime, FixedOffset, NaiveDate, Utc};
 use derive_more::From;
+use hashtrack::context::localconfig;
+use hashtrack::context::{Context, ContextError, Settings};
+use hashtrack::{api, common, session, track, tweet, user};
+use indicatif::ProgressBar;
+use notification::Notifier;
+use opts::{
+    ColorChoice, ConfigCommand, ConfigKey, CountCommand, HashtrackCommand, HashtrackOpt, ListOpt,
+    SearchOpt, SortOrder, TrackSort, TracksAction, TracksOpt, TweetGroup, WatchOpt,
+};
+use regex::RegexBuilder;
+use reqwest::Url;
 use rpassword::read_password_from_tty;
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+use serde::Serialize;
+use std::cmp;
+use std::collections::{BTreeMap, HashSet};
+use std::env;
+use std::fmt;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::future::Future;
 use std::io;
+use std::io::IsTerminal;
+use std::io::Write;
+use std::iter;
+use std::path::PathBuf;
+use std::process;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+use structopt::clap::{ErrorKind, Shell};
 use structopt::StructOpt;
+use terminal_size::Width;
 use text_io::read;
+use tokio::signal;
+use tokio::time;
+use tracing::{info, instrument, warn};
+use tracing_subscriber::EnvFilter;
 
-mod api;
-mod common;
-mod context;
+mod notification;
 mod opts;
-mod session;
-mod track;
-mod tweet;
-mod user;
+
+const INITIAL_RECONNECT_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(30);
+const DEFAULT_PAGER: &str = "less -FRX";
+const SPINNER_TICK: Duration = Duration::from_millis(80);
+/// The only variables read from `./.env`.
+const DOTENV_VARIABLES: [&str; 2] = ["HASHTRACK_ENDPOINT", "HASHTRACK_TOKEN"];
 
 #[derive(Debug, From)]
 enum CliError {
     IoError(io::Error),
     ApiError(api::ApiError),
+    Json(serde_json::Error),
+    Csv(csv::Error),
+    Usage(String),
+    #[from(ignore)]
+    Failure(String),
+}
+
+/// The broad class of a `CliError`, reported to scripts in `--json` mode.
+#[derive(Clone, Copy)]
+enum CliErrorKind {
+    Usage,
+    Api,
+    Io,
+    Failure,
+}
+
+impl CliErrorKind {
+    fn name(self) -> &'static str {
+        match self {
+            CliErrorKind::Usage => "usage",
+            CliErrorKind::Api => "api",
+            CliErrorKind::Io => "io",
+            CliErrorKind::Failure => "failure",
+        }
+    }
+}
+
+impl CliError {
+    fn kind(&self) -> CliErrorKind {
+        match self {
+            CliError::Usage(_) | CliError::ApiError(api::ApiError::NoEndpoint) => {
+                CliErrorKind::Usage
+            }
+            CliError::ApiError(_) => CliErrorKind::Api,
+            CliError::IoError(_) | CliError::Json(_) | CliError::Csv(_) => CliErrorKind::Io,
+            CliError::Failure(_) => CliErrorKind::Failure,
+        }
+    }
+
+    fn exit_code(&self) -> i32 {
+        match self {
+            CliError::Usage(_) | CliError::ApiError(api::ApiError::NoEndpoint) => 2,
+            CliError::ApiError(api::ApiError::Unauthorized) => 3,
+            _ => 1,
+        }
+    }
+}
+
+impl From<ContextError> for CliError {
+    fn from(error: ContextError) -> Self {
+        if error.is_usage() {
+            CliError::Usage(error.to_string())
+        } else {
+            CliError::Failure(error.to_string())
+        }
+    }
+}
+
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CliError::IoError(error) => write!(f, "{}", error),
+            CliError::ApiError(error) => write!(f, "{}", error),
+            CliError::Json(error) => write!(f, "{}", error),
+            CliError::Csv(error) => write!(f, "{}", error),
+            CliError::Usage(message) | CliError::Failure(message) => write!(f, "{}", message),
+        }
+    }
 }
 
-async fn login(context: &mut Context) -> Result<(), CliError> {
+/// Logs go to stderr, filtered by RUST_LOG when set and by the number of
+/// `-v` flags otherwise.
+fn init_tracing(verbose: u8) {
+    let filter = match env::var("RUST_LOG") {
+        Ok(_) => EnvFilter::from_default_env(),
+        Err(_) if verbose > 1 => EnvFilter::new("hashtrack=trace"),
+        Err(_) if verbose > 0 => EnvFilter::new("hashtrack=debug"),
+        Err(_) => return,
+    };
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(io::stderr)
+        .with_ansi(io::stderr().is_terminal())
+        .init();
+}
+
+/// Resolves the flags, `./.env` and the HASHTRACK_* variables into the
+/// settings of the context.
+fn settings_from_opt(opt: &HashtrackOpt) -> Result<Settings, CliError> {
+    let (config_path, legacy_config_path) = match &opt.config {
+        Some(path) => (PathBuf::from(path), None),
+        None => {
+            let config_dir = dirs::config_dir().ok_or_else(|| {
+                CliError::Failure(String::from(
+                    "Could not find the config directory, please use the --config flag",
+                ))
+            })?;
+            (
+                config_dir.join("hashtrack").join("config.toml"),
+                dirs::home_dir().map(|home| home.join(".hashtrack.config")),
+            )
+        }
+    };
+    if !opt.no_dotenv {
+        load_dotenv();
+    }
+    // The --endpoint flag wins over HASHTRACK_ENDPOINT, which wins over the
+    // endpoint stored in the profile.
+    let endpoints = if !opt.endpoint.is_empty() {
+        opt.endpoint.clone()
+    } else {
+        non_empty_var("HASHTRACK_ENDPOINT").into_iter().collect()
+    };
+    if opt.insecure {
+        eprintln!(
+            "WARNING: TLS certificate verification is disabled, never use --insecure in production"
+        );
+    }
+    let defaults = Settings {
+        legacy_config_path,
+        profile: opt.profile.clone(),
+        ..Settings::new(config_path)
+    };
+    // The flag wins over the profile's setting, an invalid stored one
+    // counts as unset
+    let color = opt
+        .color
+        .or_else(|| defaults.stored_color()?.parse().ok())
+        .unwrap_or(ColorChoice::Auto);
+    let wrap = opt
+        .wrap
+        .or_else(|| terminal_size::terminal_size().map(|(Width(width), _)| usize::from(width)))
+        .unwrap_or(defaults.wrap);
+    Ok(Settings {
+        endpoints,
+        token: non_empty_var("HASHTRACK_TOKEN"),
+        json: opt.json,
+        color: color.enabled(),
+        absolute_time: opt.absolute_time,
+        plain: opt.plain,
+        format: opt.format.clone(),
+        compact: opt.compact,
+        verbose: opt.verbose,
+        timeout: opt.timeout,
+        retries: if opt.no_retry { 0 } else { opt.retries },
+        retry: !opt.no_retry,
+        dry_run: opt.dry_run,
+        quiet: opt.quiet,
+        wrap,
+        pager: !opt.no_pager,
+        user_agent: opt.user_agent.clone(),
+        proxy: opt.proxy.clone(),
+        compression: !opt.no_compression,
+        insecure: opt.insecure,
+        ..defaults
+    })
+}
+
+/// Adds HASHTRACK_ENDPOINT and HASHTRACK_TOKEN from `./.env` to the
+/// environment, without overriding them when they are already set. Anything
+/// else in the file is ignored, it could be meant for another program.
+fn load_dotenv() {
+    let entries = match dotenvy::from_path_iter(".env") {
+        Ok(entries) => entries,
+        Err(dotenvy::Error::Io(error)) if error.kind() == io::ErrorKind::NotFound => return,
+        Err(error) => {
+            eprintln!("Warning: could not load .env: {}", error);
+            return;
+        }
+    };
+    for entry in entries {
+        match entry {
+            Ok((key, value)) if DOTENV_VARIABLES.contains(&key.as_str()) => {
+                if env::var_os(&key).is_none() {
+                    env::set_var(key, value);
+                }
+            }
+            Ok(_) => (),
+            Err(error) => {
+                eprintln!("Warning: could not load .env: {}", error);
+                return;
+            }
+        }
+    }
+}
+
+fn non_empty_var(key: &str) -> Option<String> {
+    env::var(key).ok().filter(|value| !value.is_empty())
+}
+
+fn print_json<T: Serialize + ?Sized>(value: &T) -> Result<(), CliError> {
+    println!("{}", serde_json::to_string(value)?);
+    Ok(())
+}
+
+fn print_csv(tweets: &[tweet::Tweet]) -> Result<(), CliError> {
+    let mut writer = csv::Writer::from_writer(io::stdout());
+    writer.write_record(["id", "created_at", "hashtag", "author", "text"])?;
+    for tweet in tweets {
+        writer.write_record([
+            tweet.id.as_str(),
+            &tweet.published_at.to_rfc3339(),
+            tweet.hashtags().first().copied().unwrap_or(""),
+            &tweet.author_name,
+            &tweet.text,
+        ])?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+fn render_all<'a>(tweets: impl Iterator<Item = &'a tweet::Tweet>, context: &Context) -> String {
+    tweets
+        .map(|tweet| format!("{}\n", tweet.render(context)))
+        .collect()
+}
+
+/// Shows a spinner on stderr until `future` completes, as long as the
+/// output is meant for a terminal and not `--quiet`.
+async fn with_spinner<F: Future>(context: &Context, message: &str, future: F) -> F::Output {
+    if context.json || context.quiet || !io::stdout().is_terminal() || !io::stderr().is_terminal() {
+        return future.await;
+    }
+    let spinner = ProgressBar::new_spinner().with_message(message.to_string());
+    spinner.enable_steady_tick(SPINNER_TICK);
+    let output = future.await;
+    spinner.finish_and_clear();
+    output
+}
+
+/// Prints `text` through `$PAGER` when writing to a terminal. The default
+/// pager exits right away when `text` fits on the screen.
+fn print_paged(context: &Context, text: &str) -> Result<(), CliError> {
+    if !context.pager || context.json || !io::stdout().is_terminal() {
+        print!("{}", text);
+        return Ok(());
+    }
+    let pager = env::var("PAGER").unwrap_or_else(|_| String::from(DEFAULT_PAGER));
+    let words = match shell_words::split(&pager) {
+        Ok(words) if !words.is_empty() => words,
+        _ => {
+            print!("{}", text);
+            return Ok(());
+        }
+    };
+    let mut child = match Command::new(&words[0])
+        .args(&words[1..])
+        .stdin(Stdio::piped())
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(error) => {
+            warn!(%error, pager = pager.as_str(), "could not start the pager");
+            print!("{}", text);
+            return Ok(());
+        }
+    };
+    if let Some(mut stdin) = child.stdin.take() {
+        // The pager closes its input when the user quits early
+        match stdin.write_all(text.as_bytes()) {
+            Err(error) if error.kind() != io::ErrorKind::BrokenPipe => return Err(error.into()),
+            _ => (),
+        }
+    }
+    child.wait()?;
+    Ok(())
+}
+
+fn read_line_from_stdin() -> io::Result<String> {
+    let mut line = String::new();
+    io::stdin().read_line(&mut line)?;
+    Ok(line.trim_end_matches(&['\r', '\n'][..]).to_string())
+}
+
+#[instrument(skip_all)]
+async fn login(
+    context: &mut Context,
+    email: Option<String>,
+    password: Option<String>,
+    password_stdin: bool,
+) -> Result<(), CliError> {
+    let email = match email {
+        Some(email) => email,
+        None => {
+            println!("Email: ");
+            read!()
+        }
+    };
+    let password = match password {
+        Some(password) => password,
+        None if password_stdin => read_line_from_stdin()?,
+        None => {
+            println!("Password: ");
+            read_password_from_tty(None)?
+        }
+    };
+    let session = session::create(context, session::Creation { email, password }).await?;
+    context.set_token(Some(session.token))?;
+    if !context.quiet {
+        println!("Login succeeded!");
+    }
+    Ok(())
+}
+
+/// Stores `token` once the server confirms it belongs to a user.
+#[instrument(skip_all)]
+async fn login_with_token(context: &mut Context, token: String) -> Result<(), CliError> {
+    let previous = context.override_token(Some(token.clone()));
+    let user = user::get_current(context).await;
+    context.override_token(previous);
+    match user {
+        Ok(_) => (),
+        Err(api::ApiError::Unauthorized) => {
+            return Err(CliError::Failure(String::from("Invalid token")));
+        }
+        Err(error) => return Err(error.into()),
+    }
+    context.set_token(Some(token))?;
+    if !context.quiet {
+        println!("Login succeeded!");
+    }
+    Ok(())
+}
+
+#[instrument(skip_all)]
+async fn register(context: &mut Context) -> Result<(), CliError> {
     println!("Email: ");
     let email: String = read!();
     println!("Password: ");
     let password = read_password_from_tty(None)?;
+    println!("Confirm password: ");
+    if read_password_from_tty(None)? != password {
+        return Err(CliError::Failure(String::from("Passwords do not match")));
+    }
+    println!("Name (optional): ");
+    let name = match read_line_from_stdin()?.trim() {
+        "" => email.split('@').next().unwrap_or(&email).to_string(),
+        name => name.to_string(),
+    };
+    user::create(
+        context,
+        user::Creation {
+            name,
+            email: email.clone(),
+            password: password.clone(),
+        },
+    )
+    .await?;
     let session = session::create(context, session::Creation { email, password }).await?;
     context.set_token(Some(session.token))?;
-    println!("Login succeeded!");
+    if !context.quiet {
+        println!("Registration succeeded, you are now logged in!");
+    }
     Ok(())
 }
 
+#[instrument(skip_all)]
 async fn status(context: &Context) -> Result<(), CliError> {
-    let user = user::get_current(context).await?;
-    println!("{:?}", user);
+    if context.token().is_none() {
+        return Err(CliError::Failure(String::from("Not logged in")));
+    }
+    let (user, tracks) = tokio::try_join!(user::get_current(context), track::get_all(context))?;
+    if context.json {
+        return print_json(&serde_json::json!({
+            "id": user.id,
+            "name": user.name,
+            "email": user.email,
+            "trackCount": tracks.len(),
+        }));
+    }
+    println!("Logged in as {} <{}>", user.name, user.email);
+    println!("Tracking {} hashtags", tracks.len());
     Ok(())
 }
 
@@ -43,68 +434,1353 @@ fn logout(context: &mut Context) -> Result<(), CliError> {
     Ok(())
 }
 
-async fn get_latest_tweets(context: &Context) -> Result<(), CliError> {
-    tweet::get_latest(context, String::from(""))
-        .await?
+fn build_hashtag_search(hashtag: Option<String>) -> String {
+    match hashtag {
+        Some(hashtag) => format!("#{}", track::normalize(&hashtag)),
+        None => String::from(""),
+    }
+}
+
+/// The latest tweets, only the ones mentioning `hashtag` when it's given. The
+/// server matches any text containing the search, `#rustlang` for `#rust`.
+async fn fetch_tweets(
+    context: &Context,
+    hashtag: Option<&str>,
+) -> Result<Vec<tweet::Tweet>, api::ApiError> {
+    match hashtag {
+        Some(hashtag) => tweet::get_latest_for_track(context, &track::normalize(hashtag))
+            .await
+            .map(|page| page.tweets),
+        None => tweet::get_latest(context, String::new()).await,
+    }
+}
+
+fn list_profiles(context: &Context) -> Result<(), CliError> {
+    let names = context.profile_names();
+    if context.json {
+        return print_json(&names);
+    }
+    names.iter().for_each(|name| {
+        let marker = if *name == context.profile { "*" } else { " " };
+        println!("{} {}", marker, name);
+    });
+    Ok(())
+}
+
+/// Prints a section per hashtag, using the first hashtag of every tweet.
+fn print_grouped_by_hashtag(context: &Context, tweets: &[tweet::Tweet]) -> Result<(), CliError> {
+    let mut groups: BTreeMap<String, Vec<&tweet::Tweet>> = BTreeMap::new();
+    for tweet in tweets.iter().rev() {
+        let hashtag = tweet.hashtags().first().map(|name| track::normalize(name));
+        groups
+            .entry(hashtag.unwrap_or_default())
+            .or_default()
+            .push(tweet);
+    }
+    if context.json {
+        return print_json(&groups);
+    }
+    let mut text = String::new();
+    for (hashtag, tweets) in &groups {
+        let header = if hashtag.is_empty() {
+            String::from("(no hashtag)")
+        } else {
+            format!("#{}", hashtag)
+        };
+        let header = common::paint(context.color, Style::new().bold().underline(), &header);
+        text.push_str(&format!("{}\n\n", header));
+        tweets.iter().for_each(|tweet| {
+            text.push_str(&format!("{}\n", tweet.render(context)));
+        });
+    }
+    print_paged(context, &text)
+}
+
+#[instrument(skip_all)]
+async fn get_latest_tweets(context: &mut Context, opt: ListOpt) -> Result<(), CliError> {
+    if opt.csv && context.json {
+        return Err(CliError::Usage(String::from(
+            "--csv and --json cannot be used together",
+        )));
+    }
+    let mut tweets = with_spinner(
+        context,
+        "Fetching tweets",
+        fetch_tweets(context, opt.hashtag.as_deref()),
+    )
+    .await?;
+    if tweets.is_empty() {
+        hint_if_nothing_tracked(context).await;
+    }
+    let mut seen = tweet::SeenTweets::new(tweet::SEEN_TWEETS_CAPACITY);
+    tweets.iter().for_each(|tweet| {
+        seen.insert(&tweet.id);
+    });
+    // Tweets come oldest first, the follow-up stream backfills after this one
+    let last_id = tweets.last().map(|tweet| tweet.id.clone());
+    if let (Some(last_seen_id), true) = (context.last_seen_tweet_id(), opt.since_last) {
+        tweets.retain(|tweet| tweet.is_newer_than(last_seen_id));
+    }
+    // The server has no date filters, so the range is applied here
+    tweets.retain(|tweet| {
+        let day = tweet.published_at.naive_utc().date();
+        opt.since.is_none_or(|since| day >= since) && opt.until.is_none_or(|until| day < until)
+    });
+    if let Some(limit) = opt.limit {
+        // Tweets come oldest first, so keep the newest ones at the end
+        tweets.drain(..tweets.len().saturating_sub(limit));
+    }
+    if opt.count {
+        println!("{}", tweets.len());
+        return Ok(());
+    }
+    if let Some(SortOrder::Desc) = opt.order {
+        tweets.reverse();
+    }
+    if let Some(TweetGroup::Hashtag) = opt.group_by {
+        print_grouped_by_hashtag(context, &tweets)?;
+    } else if opt.follow {
+        for tweet in &tweets {
+            print_streamed(context, tweet)?;
+        }
+    } else if context.json {
+        print_json(&tweets)?;
+    } else if opt.csv {
+        print_csv(&tweets)?;
+    } else {
+        print_paged(context, &render_all(tweets.iter(), context))?;
+    }
+    // The marker is shared by the whole profile, so only a full listing moves it
+    let filtered = opt.hashtag.is_some() || opt.since.is_some() || opt.until.is_some();
+    if !filtered {
+        remember_last_seen(context, &tweets);
+    }
+    if !opt.follow {
+        return Ok(());
+    }
+    let watch = WatchOpt {
+        hashtag: opt.hashtag,
+        max_retries: None,
+        notify: false,
+        output: None,
+        tee: None,
+        output_dir: None,
+        no_stdout: false,
+        count_only: false,
+        max_tweets: None,
+        timeout_idle: None,
+        retry_on_auth: false,
+    };
+    stream_latest_tweets(context, watch, seen, last_id).await
+}
+
+/// Prints a tweet of a stream, as one JSON object per line with `--json`.
+fn print_streamed(context: &Context, tweet: &tweet::Tweet) -> Result<(), CliError> {
+    if context.json {
+        print_json(tweet)
+    } else {
+        println!("{}", tweet.render(context));
+        Ok(())
+    }
+}
+
+/// Stores the newest of the printed `tweets` for `--since-last`. Failing to
+/// do so only affects the next run, so it doesn't fail this one.
+fn remember_last_seen(context: &mut Context, tweets: &[tweet::Tweet]) {
+    let newest = tweets.iter().reduce(|newest, tweet| {
+        if tweet.is_newer_than(&newest.id) {
+            tweet
+        } else {
+            newest
+        }
+    });
+    let newest = match (newest, context.last_seen_tweet_id()) {
+        (Some(newest), Some(last_seen_id)) if !newest.is_newer_than(last_seen_id) => return,
+        (Some(newest), _) => newest.id.clone(),
+        (None, _) => return,
+    };
+    match context.set_last_seen_tweet_id(newest) {
+        Err(error) if !context.quiet => {
+            eprintln!("Warning: could not remember the last seen tweet: {}", error)
+        }
+        _ => (),
+    }
+}
+
+/// Explains on stderr why there are no tweets when nothing is tracked, as
+/// opposed to nothing being published lately.
+async fn hint_if_nothing_tracked(context: &Context) {
+    if context.quiet {
+        return;
+    }
+    match track::get_all(context).await {
+        Ok(tracks) if tracks.is_empty() => {
+            eprintln!("No hashtags tracked yet. Use `hashtrack track <hashtag>` to start.")
+        }
+        Ok(_) => (),
+        Err(error) => warn!(%error, "could not check for tracks"),
+    }
+}
+
+#[instrument(skip_all)]
+async fn search_tweets(context: &Context, opt: SearchOpt) -> Result<(), CliError> {
+    let patterns = opt
+        .terms
         .iter()
-        .for_each(|tweet| {
-            println!("{}", tweet);
+        .map(|term| {
+            let pattern = if opt.regex {
+                term.clone()
+            } else {
+                regex::escape(term)
+            };
+            RegexBuilder::new(&pattern)
+                .case_insensitive(true)
+                .build()
+                .map_err(|error| CliError::Usage(error.to_string()))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut tweets = tweet::get_latest(context, String::new()).await?;
+    tweets.retain(|tweet| tweet.matches_all(&patterns));
+    if tweets.is_empty() {
+        return Err(CliError::Failure(String::from("no matches")));
+    }
+    if context.json {
+        return print_json(&tweets);
+    }
+    print_paged(context, &render_all(tweets.iter(), context))
+}
+
+/// Prints new tweets as they arrive, skipping the ones already in `seen`.
+/// With `last_id`, the tweets published after it are backfilled first.
+#[instrument(skip_all)]
+async fn stream_latest_tweets(
+    context: &mut Context,
+    opt: WatchOpt,
+    seen: tweet::SeenTweets,
+    last_id: Option<String>,
+) -> Result<(), CliError> {
+    if context.endpoints.is_empty() {
+        return Err(api::ApiError::NoEndpoint.into());
+    }
+    let search = build_hashtag_search(opt.hashtag.clone());
+    let quiet = context.quiet;
+    let mut counter = TweetCounter::new();
+    let counter_ref = if opt.count_only {
+        Some(&mut counter)
+    } else {
+        None
+    };
+    let result = tokio::select! {
+        result = follow_tweet_stream(context, search, &opt, seen, last_id, counter_ref) => result,
+        _ = signal::ctrl_c() => {
+            // Dropping the watch drops its stream, which stops the subscription
+            // on the server and closes the connection
+            if !quiet {
+                eprintln!("Stopping watch...");
+            }
+            Ok(())
+        }
+    };
+    if opt.count_only {
+        counter.finish();
+    }
+    result
+}
+
+/// Counts the tweets of a stream for `watch --count-only`.
+struct TweetCounter {
+    count: u64,
+    started_at: Instant,
+}
+
+impl TweetCounter {
+    fn new() -> Self {
+        TweetCounter {
+            count: 0,
+            started_at: Instant::now(),
+        }
+    }
+
+    /// Counts one more tweet, updating the line on stderr when it's a terminal.
+    fn record(&mut self) {
+        self.count += 1;
+        if io::stderr().is_terminal() {
+            eprint!("\r\x1b[Kreceived: {}", self.summary());
+        }
+    }
+
+    /// Replaces the running count with the final total.
+    fn finish(&self) {
+        let clear = if io::stderr().is_terminal() {
+            "\r\x1b[K"
+        } else {
+            ""
+        };
+        eprintln!("{}total: {}", clear, self.summary());
+    }
+
+    fn summary(&self) -> String {
+        let minutes = self.started_at.elapsed().as_secs_f64() / 60.0;
+        let rate = if minutes > 0.0 {
+            self.count as f64 / minutes
+        } else {
+            0.0
+        };
+        format!("{} tweets ({:.1}/min)", self.count, rate)
+    }
+}
+
+/// Appends lines to one file per UTC day in a directory.
+struct DailyLog {
+    directory: PathBuf,
+    day: Option<NaiveDate>,
+    file: Option<File>,
+}
+
+impl DailyLog {
+    fn new(directory: PathBuf) -> Self {
+        DailyLog {
+            directory,
+            day: None,
+            file: None,
+        }
+    }
+
+    /// Writes `line` to today's file, opening a new one when the day changed,
+    /// and syncs it so readers following the file see it right away.
+    fn append(&mut self, line: &str) -> io::Result<()> {
+        let today = Utc::now().date_naive();
+        let file = match &mut self.file {
+            Some(file) if self.day == Some(today) => file,
+            _ => {
+                fs::create_dir_all(&self.directory)?;
+                let path = self
+                    .directory
+                    .join(format!("tweets-{}.ndjson", today.format("%Y-%m-%d")));
+                self.day = Some(today);
+                self.file
+                    .insert(OpenOptions::new().create(true).append(true).open(path)?)
+            }
+        };
+        writeln!(file, "{}", line)?;
+        file.sync_data()
+    }
+}
+
+/// Prints every tweet of a stream once, remembering the newest one.
+struct StreamPrinter<'a> {
+    opt: &'a WatchOpt,
+    seen: tweet::SeenTweets,
+    output: Option<File>,
+    tee: Option<File>,
+    daily_log: Option<DailyLog>,
+    notifier: Notifier,
+    last_id: Option<String>,
+    counter: Option<&'a mut TweetCounter>,
+    emitted: usize,
+}
+
+impl StreamPrinter<'_> {
+    fn emit(&mut self, context: &Context, tweet: &tweet::Tweet) -> Result<(), CliError> {
+        // The subscription matches the hashtag as a substring like a query
+        let unrelated = self
+            .opt
+            .hashtag
+            .as_ref()
+            .is_some_and(|hashtag| !tweet.mentions(&track::normalize(hashtag)));
+        if unrelated || self.is_done() || !self.seen.insert(&tweet.id) {
+            return Ok(());
+        }
+        self.emitted += 1;
+        if self
+            .last_id
+            .as_deref()
+            .is_none_or(|id| tweet.is_newer_than(id))
+        {
+            self.last_id = Some(tweet.id.clone());
+        }
+        if let Some(file) = &mut self.output {
+            writeln!(file, "{}", tweet.log_line())?;
+            file.flush()?;
+        }
+        if let Some(file) = &mut self.tee {
+            writeln!(file, "{}", serde_json::to_string(tweet)?)?;
+            file.flush()?;
+        }
+        if let Some(log) = &mut self.daily_log {
+            log.append(&serde_json::to_string(tweet)?)?;
+        }
+        if let Some(counter) = &mut self.counter {
+            counter.record();
+        } else if !self.opt.no_stdout {
+            print_streamed(context, tweet)?;
+        }
+        if self.opt.notify {
+            self.notifier.notify(tweet);
+        }
+        Ok(())
+    }
+
+    /// Whether `--max-tweets` tweets were printed already.
+    fn is_done(&self) -> bool {
+        self.opt
+            .max_tweets
+            .is_some_and(|max_tweets| self.emitted >= max_tweets)
+    }
+
+    /// Prints the tweets published after the last one seen, which the stream
+    /// missed while it was disconnected.
+    async fn backfill(&mut self, context: &Context, search: String) {
+        let last_id = match self.last_id.clone() {
+            Some(last_id) => last_id,
+            None => return,
+        };
+        match tweet::get_latest(context, search).await {
+            Ok(tweets) => {
+                for tweet in tweets.iter().filter(|tweet| tweet.is_newer_than(&last_id)) {
+                    if let Err(error) = self.emit(context, tweet) {
+                        warn!(%error, "could not print a backfilled tweet");
+                    }
+                }
+            }
+            Err(error) => warn!(%error, "could not backfill tweets missed while reconnecting"),
+        }
+    }
+}
+
+async fn follow_tweet_stream(
+    context: &mut Context,
+    search: String,
+    opt: &WatchOpt,
+    seen: tweet::SeenTweets,
+    last_id: Option<String>,
+    counter: Option<&mut TweetCounter>,
+) -> Result<(), CliError> {
+    let mut retries = 0;
+    let mut backoff = INITIAL_RECONNECT_BACKOFF;
+    let open_append = |path: &PathBuf| OpenOptions::new().create(true).append(true).open(path);
+    let output = opt.output.as_ref().map(open_append).transpose()?;
+    let tee = opt.tee.as_ref().map(open_append).transpose()?;
+    let mut printer = StreamPrinter {
+        opt,
+        seen,
+        output,
+        tee,
+        daily_log: opt.output_dir.clone().map(DailyLog::new),
+        notifier: Notifier::default(),
+        last_id,
+        counter,
+        emitted: 0,
+    };
+    // The server sends no keep-alives, so a quiet hashtag can't be told apart
+    // from a dead connection unless the user asks for it
+    let idle_timeout = opt
+        .timeout_idle
+        .filter(|seconds| *seconds > 0)
+        .map(Duration::from_secs);
+    // Following a listing catches up on what was published since it
+    let mut reconnecting = printer.last_id.is_some();
+    loop {
+        // Subscribe before backfilling so nothing published in between is
+        // missed, the seen set drops whatever arrives twice
+        let mut stream = tweet::stream_latest(context, search.clone());
+        if reconnecting {
+            printer.backfill(context, search.clone()).await;
+        }
+        if printer.is_done() {
+            return Ok(());
+        }
+        let mut unauthorized = false;
+        let mut idle = false;
+        loop {
+            let event = match idle_timeout {
+                Some(idle_timeout) => match time::timeout(idle_timeout, stream.recv()).await {
+                    Ok(event) => event,
+                    Err(_) => {
+                        info!(?idle_timeout, "tweet stream is idle");
+                        idle = true;
+                        break;
+                    }
+                },
+                None => stream.recv().await,
+            };
+            let event = match event {
+                Some(event) => event,
+                None => break,
+            };
+            retries = 0;
+            backoff = INITIAL_RECONNECT_BACKOFF;
+            match event {
+                Ok(tweet::StreamEvent::Tweet(tweet)) => {
+                    printer.emit(context, &tweet)?;
+                    if printer.is_done() {
+                        // Dropping the stream stops the subscription
+                        return Ok(());
+                    }
+                }
+                Ok(tweet::StreamEvent::KeepAlive) => (),
+                Err(api::ApiError::Unauthorized) if opt.retry_on_auth => unauthorized = true,
+                Err(error) => return Err(error.into()),
+            }
+        }
+        // Also ends the thread of the old subscription before the next one
+        stream.close();
+        if unauthorized {
+            log_in_again(context).await?;
+            reconnecting = true;
+            continue;
+        }
+        if idle {
+            // Nothing failed, so this isn't counted towards --max-retries
+            reconnecting = true;
+            continue;
+        }
+        if opt
+            .max_retries
+            .is_some_and(|max_retries| retries >= max_retries)
+        {
+            return Err(CliError::Failure(String::from(
+                "Lost the connection to the tweet stream",
+            )));
+        }
+        retries += 1;
+        reconnecting = true;
+        info!(retries, ?backoff, "tweet stream closed, reconnecting");
+        if !context.quiet {
+            eprintln!(
+                "{}",
+                common::paint(context.color, Style::new().dimmed(), "reconnecting...")
+            );
+        }
+        time::delay_for(backoff).await;
+        backoff = cmp::min(backoff * 2, MAX_RECONNECT_BACKOFF);
+    }
+}
+
+/// Asks for the credentials again after the token expired during
+/// `watch --retry-on-auth`.
+async fn log_in_again(context: &mut Context) -> Result<(), CliError> {
+    if context.token_from_env() {
+        return Err(CliError::Failure(String::from(
+            "The server rejected HASHTRACK_TOKEN, set a valid token to keep watching",
+        )));
+    }
+    if !io::stdin().is_terminal() {
+        return Err(api::ApiError::Unauthorized.into());
+    }
+    eprintln!("Your session expired, please log in again");
+    login(context, None, None, false).await
+}
+
+async fn export_tracks(context: &Context, path: PathBuf) -> Result<(), CliError> {
+    let tracks = track::get_all(context).await?;
+    let mut file = File::create(&path)?;
+    for track in &tracks {
+        writeln!(file, "{}", track.hashtag_name)?;
+    }
+    if !context.quiet {
+        println!("Exported {} tracks to {}", tracks.len(), path.display());
+    }
+    Ok(())
+}
+
+fn is_comment(line: &str) -> bool {
+    match line.strip_prefix('#') {
+        Some(rest) => rest.is_empty() || rest.starts_with(char::is_whitespace),
+        None => false,
+    }
+}
+
+/// Replaces a `-` argument with the hashtags read from stdin, one per line,
+/// skipping blank and `# ` comment lines.
+fn with_stdin_hashtags(hashtags: Vec<String>) -> Result<Vec<String>, CliError> {
+    if !hashtags.iter().any(|hashtag| hashtag == "-") {
+        return Ok(hashtags);
+    }
+    let input = io::read_to_string(io::stdin())?;
+    let mut result = Vec::new();
+    for hashtag in hashtags {
+        if hashtag == "-" {
+            result.extend(
+                input
+                    .lines()
+                    .map(str::trim)
+                    .filter(|line| !line.is_empty() && !is_comment(line))
+                    .map(String::from),
+            );
+        } else {
+            result.push(hashtag);
+        }
+    }
+    Ok(result)
+}
+
+async fn import_tracks(context: &mut Context, path: PathBuf) -> Result<(), CliError> {
+    let contents = fs::read_to_string(&path)?;
+    let hashtags = contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !is_comment(line))
+        .map(String::from)
+        .collect();
+    run_track_batch(context, TrackAction::Create, hashtags).await
+}
+
+#[instrument(skip_all)]
+async fn list_tracks(context: &mut Context, opt: TracksOpt) -> Result<(), CliError> {
+    match opt.action {
+        Some(TracksAction::Export { path }) => return export_tracks(context, path).await,
+        Some(TracksAction::Import { path }) => return import_tracks(context, path).await,
+        None => (),
+    }
+    let (mut tracks, cached_at) = if opt.offline {
+        let (cached_at, tracks) = track::load_cached(context).ok_or_else(|| {
+            CliError::Failure(String::from(
+                "No recent tracks are cached, run `hashtrack tracks` while online first",
+            ))
+        })?;
+        (tracks, Some(cached_at))
+    } else {
+        match with_spinner(context, "Fetching tracks", track::get_all(context)).await {
+            Ok(tracks) => (tracks, None),
+            Err(error) if error.is_transient() => match track::load_cached(context) {
+                Some((cached_at, tracks)) => {
+                    warn!(%error, "showing cached tracks");
+                    (tracks, Some(cached_at))
+                }
+                None => return Err(error.into()),
+            },
+            Err(error) => return Err(error.into()),
+        }
+    };
+    if let (Some(cached_at), false) = (cached_at, context.quiet) {
+        eprintln!(
+            "(cached at {}, {})",
+            cached_at.format("%Y-%m-%d %H:%M"),
+            common::format_relative(&cached_at)
+        );
+    }
+    if opt.count {
+        println!("{}", tracks.len());
+        return Ok(());
+    }
+    match opt.sort {
+        Some(TrackSort::Name) => tracks.sort_by_key(|track| track.pretty_name.to_lowercase()),
+        Some(TrackSort::Created) => tracks.sort_by_key(|track| track.created_at),
+        None => (),
+    }
+    if opt.reverse {
+        tracks.reverse();
+    }
+    if context.json {
+        return print_json(&tracks);
+    }
+    let text: String = tracks
+        .iter()
+        .map(|track| {
+            if opt.names_only {
+                format!("{}\n", track.hashtag_name)
+            } else if opt.verbose {
+                format!("{}\n", track.render_verbose(context))
+            } else {
+                format!("{}\n", track.render(context))
+            }
+        })
+        .collect();
+    print_paged(context, &text)
+}
+
+#[derive(Clone, Copy)]
+enum TrackAction {
+    Create,
+    Remove,
+}
+
+impl TrackAction {
+    fn verb(self) -> &'static str {
+        match self {
+            TrackAction::Create => "track",
+            TrackAction::Remove => "untrack",
+        }
+    }
+
+    fn past_tense(self) -> &'static str {
+        match self {
+            TrackAction::Create => "Tracked",
+            TrackAction::Remove => "Untracked",
+        }
+    }
+
+    fn success_message(self, track: &track::Track) -> String {
+        match self {
+            TrackAction::Create => format!("Now tracking {}...", track.pretty_name),
+            TrackAction::Remove => format!("Stopped tracking {}", track.pretty_name),
+        }
+    }
+
+    async fn apply(
+        self,
+        context: &Context,
+        hashtag: String,
+    ) -> Result<track::Track, api::ApiError> {
+        match self {
+            TrackAction::Create => track::create(context, track::Creation { hashtag }).await,
+            TrackAction::Remove => track::remove(context, track::Removal { hashtag }).await,
+        }
+    }
+}
+
+/// The outcome of a batch, printed last so scripts can parse it.
+struct BatchSummary {
+    action: TrackAction,
+    succeeded: usize,
+    skipped: usize,
+    failed: usize,
+}
+
+impl BatchSummary {
+    fn to_json(&self) -> serde_json::Value {
+        let mut summary = serde_json::json!({ "skipped": self.skipped, "failed": self.failed });
+        summary[self.action.past_tense().to_lowercase()] = self.succeeded.into();
+        summary
+    }
+}
+
+impl fmt::Display for BatchSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "SUMMARY {}={} skipped={} failed={}",
+            self.action.past_tense().to_lowercase(),
+            self.succeeded,
+            self.skipped,
+            self.failed
+        )
+    }
+}
+
+/// Applies `action` to every hashtag, reporting each outcome instead of
+/// stopping at the first failure. Hashtags that are already tracked are
+/// skipped when creating.
+#[instrument(skip_all)]
+async fn run_track_batch(
+    context: &mut Context,
+    action: TrackAction,
+    hashtags: Vec<String>,
+) -> Result<(), CliError> {
+    let mut invalid = 0;
+    let hashtags = match action {
+        TrackAction::Create if hashtags.len() == 1 => {
+            vec![track::validate(&hashtags[0])
+                .map_err(CliError::Usage)?
+                .to_string()]
+        }
+        // An invalid hashtag in a batch fails on its own like a rejected one
+        TrackAction::Create => hashtags
+            .iter()
+            .filter_map(|hashtag| match track::validate(hashtag) {
+                Ok(hashtag) => Some(hashtag.to_string()),
+                Err(message) => {
+                    eprintln!("{}", message);
+                    invalid += 1;
+                    None
+                }
+            })
+            .collect(),
+        TrackAction::Remove => hashtags.iter().map(|h| track::normalize(h)).collect(),
+    };
+    // A repeated hashtag would fail the second time, so it's skipped instead
+    let mut names = HashSet::new();
+    let (hashtags, repeated): (Vec<String>, Vec<String>) = hashtags
+        .into_iter()
+        .partition(|hashtag| names.insert(track::normalize(hashtag)));
+    if !context.json && !context.quiet {
+        repeated.iter().for_each(|hashtag| {
+            println!("Skipping repeated #{}", hashtag);
+        });
+    }
+    let mut skipped = repeated.len();
+    let hashtags = if let TrackAction::Create = action {
+        // The server overwrites a duplicate track instead of rejecting it, so
+        // duplicates are recognized here to report them as skipped
+        let tracked: HashSet<String> = track::get_all(context)
+            .await?
+            .into_iter()
+            .map(|track| track.hashtag_name)
+            .collect();
+        let (present, new): (Vec<String>, Vec<String>) = hashtags
+            .into_iter()
+            .partition(|hashtag| tracked.contains(&track::normalize(hashtag)));
+        if !context.json && !context.quiet {
+            present.iter().for_each(|hashtag| {
+                println!("Already tracking #{}", hashtag);
+            });
+        }
+        skipped += present.len();
+        new
+    } else {
+        hashtags
+    };
+    if context.dry_run {
+        hashtags.iter().for_each(|hashtag| {
+            println!("Would {} #{}", action.verb(), hashtag);
         });
+        if skipped > 0 {
+            println!(
+                "{} would be {}, {} skipped",
+                hashtags.len(),
+                action.past_tense().to_lowercase(),
+                skipped
+            );
+        }
+        return Ok(());
+    }
+    let total = hashtags.len() + invalid;
+    let mut tracks = Vec::new();
+    let mut failures = Vec::new();
+    for hashtag in hashtags {
+        match action.apply(context, hashtag.clone()).await {
+            Ok(track) => {
+                if !context.json && !context.quiet {
+                    println!("{}", action.success_message(&track));
+                }
+                tracks.push(track);
+            }
+            Err(error) => {
+                if total > 1 {
+                    eprintln!("Could not {} {}: {}", action.verb(), hashtag, error);
+                }
+                failures.push((hashtag, error));
+            }
+        }
+    }
+    let summary = BatchSummary {
+        action,
+        succeeded: tracks.len(),
+        skipped,
+        failed: failures.len() + invalid,
+    };
+    if context.json {
+        print_json(&serde_json::json!({ "tracks": tracks, "summary": summary.to_json() }))?;
+    } else {
+        if !context.quiet && total + skipped != 1 {
+            println!(
+                "{} {} of {} hashtags",
+                action.past_tense(),
+                tracks.len(),
+                total
+            );
+        }
+        // Scripts parse this line, so it's printed even for a single hashtag
+        // and with --quiet
+        println!("{}", summary);
+    }
+    match summary.failed {
+        0 => Ok(()),
+        1 if total == 1 => Err(failures.remove(0).1.into()),
+        failed => Err(CliError::Failure(format!(
+            "Failed to {} {} of {} hashtags",
+            action.verb(),
+            failed,
+            total
+        ))),
+    }
+}
+
+fn confirm(prompt: &str) -> Result<bool, CliError> {
+    if !io::stdin().is_terminal() {
+        return Err(CliError::Usage(String::from(
+            "Cannot ask for confirmation because stdin is not a terminal, pass --yes to proceed",
+        )));
+    }
+    print!("{} [y/N] ", prompt);
+    io::stdout().flush()?;
+    let answer = read_line_from_stdin()?.trim().to_lowercase();
+    Ok(answer == "y" || answer == "yes")
+}
+
+async fn remove_tracks(
+    context: &mut Context,
+    hashtags: Vec<String>,
+    all: bool,
+    yes: bool,
+) -> Result<(), CliError> {
+    if !all {
+        let hashtags = with_stdin_hashtags(hashtags)?;
+        let names: Vec<String> = hashtags
+            .iter()
+            .map(|name| format!("#{}", track::normalize(name)))
+            .collect();
+        if !yes && !context.dry_run && !confirm(&format!("Stop tracking {}?", names.join(", ")))? {
+            return Ok(());
+        }
+        return run_track_batch(context, TrackAction::Remove, hashtags).await;
+    }
+    let hashtags: Vec<String> = track::get_all(context)
+        .await?
+        .into_iter()
+        .map(|track| track.hashtag_name)
+        .collect();
+    if hashtags.is_empty() {
+        if !context.quiet {
+            println!("There are no tracks to remove");
+        }
+        return Ok(());
+    }
+    if !yes && !context.dry_run && !confirm(&format!("Remove all {} tracks?", hashtags.len()))? {
+        return Ok(());
+    }
+    run_track_batch(context, TrackAction::Remove, hashtags).await
+}
+
+/// Removes the tracks whose newest tweet is older than `older_than`. Only
+/// the latest tweets the server returns for each track are considered, so a
+/// track without any counts as inactive.
+async fn prune_tracks(
+    context: &mut Context,
+    older_than: chrono::Duration,
+    yes: bool,
+) -> Result<(), CliError> {
+    let hashtags: Vec<String> = track::find_inactive(context, Utc::now() - older_than)
+        .await?
+        .into_iter()
+        .map(|track| track.hashtag_name)
+        .collect();
+    if hashtags.is_empty() {
+        if !context.quiet {
+            println!("There are no inactive tracks to remove");
+        }
+        return Ok(());
+    }
+    let names: Vec<String> = hashtags.iter().map(|name| format!("#{}", name)).collect();
+    if !yes && !context.dry_run && !confirm(&format!("Stop tracking {}?", names.join(", ")))? {
+        return Ok(());
+    }
+    run_track_batch(context, TrackAction::Remove, hashtags).await
+}
+
+async fn ping(context: &Context) -> Result<(), CliError> {
+    let elapsed = api::ping(context).await?;
+    println!("OK ({}ms)", elapsed.as_millis());
     Ok(())
 }
 
-fn stream_latest_tweets(context: &Context) -> Result<(), CliError> {
-    let receiver = tweet::stream_latest(context, String::from(""));
-    loop {
-        match receiver.recv() {
-            Ok(tweet) => println!("{}", tweet),
-            Err(_) => break,
-        };
+async fn server_info(context: &Context) -> Result<(), CliError> {
+    let server_version = api::server_version(context).await?;
+    if context.json {
+        return print_json(&serde_json::json!({
+            "cliVersion": env!("CARGO_PKG_VERSION"),
+            "serverVersion": server_version,
+        }));
+    }
+    println!("hashtrack-cli {}", env!("CARGO_PKG_VERSION"));
+    match server_version {
+        Some(version) => println!("server {}", version),
+        None => println!("server version unknown"),
+    }
+    Ok(())
+}
+
+fn show_config(context: &Context) -> Result<(), CliError> {
+    // HASHTRACK_TOKEN is used without being stored, so the source is shown
+    let token_source = if context.token_from_env() {
+        Some("env")
+    } else if context
+        .stored_profile()
+        .is_some_and(|stored| stored.token.is_some())
+    {
+        Some("stored")
+    } else {
+        None
+    };
+    if context.json {
+        return print_json(&serde_json::json!({
+            "endpoint": context.endpoint(),
+            "endpoints": context.endpoints.iter().map(Url::as_str).collect::<Vec<_>>(),
+            "configPath": context.config_path(),
+            "profile": context.profile,
+            "loggedIn": token_source.is_some(),
+            "tokenSource": token_source,
+        }));
     }
+    let endpoints: Vec<&str> = context.endpoints.iter().map(Url::as_str).collect();
+    println!("Endpoint: {}", endpoints.join(", "));
+    println!("Config file: {}", context.config_path().display());
+    println!("Profile: {}", context.profile);
+    println!("Token: {}", token_source.unwrap_or("none"));
     Ok(())
 }
 
-async fn list_tracks(context: &Context) -> Result<(), CliError> {
-    track::get_all(context).await?.iter().for_each(|track| {
-        println!("{}", track);
+#[derive(Serialize)]
+struct TrackStats {
+    hashtag: String,
+    #[serde(rename = "tweetCount")]
+    tweet_count: usize,
+    #[serde(rename = "latestTweetAt")]
+    latest_tweet_at: Option<DateTime<FixedOffset>>,
+    /// Whether the track has more tweets than were counted.
+    truncated: bool,
+}
+
+#[instrument(skip_all)]
+async fn stats(context: &Context, limit: Option<usize>) -> Result<(), CliError> {
+    let mut stats = Vec::new();
+    for track in track::get_all(context).await? {
+        let page = tweet::get_latest_for_track(context, &track.hashtag_name).await?;
+        let mut tweets = page.tweets;
+        let mut truncated = page.truncated;
+        if let Some(limit) = limit {
+            truncated |= tweets.len() > limit;
+            tweets.drain(..tweets.len().saturating_sub(limit));
+        }
+        stats.push(TrackStats {
+            hashtag: track.pretty_name,
+            tweet_count: tweets.len(),
+            latest_tweet_at: tweets.iter().map(|tweet| tweet.published_at).max(),
+            truncated,
+        });
+    }
+    stats.sort_by(|a, b| {
+        b.tweet_count
+            .cmp(&a.tweet_count)
+            .then_with(|| a.hashtag.to_lowercase().cmp(&b.hashtag.to_lowercase()))
     });
+    if context.json {
+        return print_json(&stats);
+    }
+    let width = stats
+        .iter()
+        .map(|stat| stat.hashtag.chars().count())
+        .chain(iter::once("HASHTAG".len()))
+        .max()
+        .unwrap_or_default();
+    println!(
+        "{:<width$}  {:>6}  LATEST",
+        "HASHTAG",
+        "TWEETS",
+        width = width
+    );
+    for stat in &stats {
+        let latest = match &stat.latest_tweet_at {
+            Some(time) if context.absolute_time => time.to_rfc3339(),
+            Some(time) => common::format_relative(time),
+            None => String::from("-"),
+        };
+        let count = if stat.truncated {
+            format!("{}+", stat.tweet_count)
+        } else {
+            stat.tweet_count.to_string()
+        };
+        println!(
+            "{:<width$}  {:>6}  {}",
+            stat.hashtag,
+            count,
+            latest,
+            width = width
+        );
+    }
     Ok(())
 }
 
-async fn create_track(context: &mut Context, hashtag: String) -> Result<(), CliError> {
-    let track = track::create(context, track::Creation { hashtag }).await?;
-    println!("Now tracking {}...", track.pretty_name);
+fn get_config(context: &Context, key: ConfigKey) -> Result<(), CliError> {
+    let stored = context.stored_profile();
+    let value = match key {
+        ConfigKey::Endpoint => stored.and_then(|stored| stored.endpoint.clone()),
+        ConfigKey::Color => stored.and_then(|stored| stored.color.clone()),
+        ConfigKey::Timeout => stored.and_then(|stored| stored.timeout.map(|t| t.to_string())),
+        ConfigKey::Format => stored.and_then(|stored| stored.format.clone()),
+    };
+    match value {
+        Some(value) => {
+            println!("{}", value);
+            Ok(())
+        }
+        None => Err(CliError::Failure(format!("{} is not set", key.name()))),
+    }
+}
+
+/// Validates `value` and stores it in the current profile.
+fn set_config(context: &mut Context, key: ConfigKey, value: String) -> Result<(), CliError> {
+    match key {
+        ConfigKey::Endpoint => {
+            // Accepts the same forms as --endpoint and HASHTRACK_ENDPOINT
+            let endpoints = localconfig::parse_endpoints(&value)
+                .map_err(|error| CliError::Usage(format!("Invalid {}", error)))?;
+            if endpoints.is_empty() {
+                return Err(CliError::Usage(String::from(
+                    "The endpoint cannot be empty",
+                )));
+            }
+            let value = value.trim().to_string();
+            context.update_stored_profile(|stored| stored.endpoint = Some(value))?;
+        }
+        ConfigKey::Color => {
+            value.parse::<ColorChoice>().map_err(CliError::Usage)?;
+            context.update_stored_profile(|stored| stored.color = Some(value))?;
+        }
+        ConfigKey::Timeout => {
+            let timeout = value.parse::<u64>().map_err(|_| {
+                CliError::Usage(format!("\"{}\" is not a number of seconds", value))
+            })?;
+            context.update_stored_profile(|stored| stored.timeout = Some(timeout))?;
+        }
+        ConfigKey::Format => {
+            context.update_stored_profile(|stored| stored.format = Some(value))?;
+        }
+    }
     Ok(())
 }
 
-async fn remove_track(context: &mut Context, hashtag: String) -> Result<(), CliError> {
-    let track = track::remove(context, track::Removal { hashtag }).await?;
-    println!("Stopped tracking {}", track.pretty_name);
+fn validate_config(context: &Context) -> Result<(), CliError> {
+    let problems = context.config_problems();
+    if problems.is_empty() {
+        if !context.quiet {
+            println!("{} is valid", context.config_path().display());
+        }
+        return Ok(());
+    }
+    problems.iter().for_each(|problem| println!("{}", problem));
+    Err(CliError::Failure(format!(
+        "Found {} problem(s) in {}",
+        problems.len(),
+        context.config_path().display()
+    )))
+}
+
+async fn count(context: &Context, command: CountCommand) -> Result<(), CliError> {
+    let total = match command {
+        CountCommand::Tracks => track::get_all(context).await?.len(),
+        CountCommand::Tweets { hashtag } => fetch_tweets(context, hashtag.as_deref()).await?.len(),
+    };
+    println!("{}", total);
     Ok(())
 }
 
+fn print_completions(shell: Shell) {
+    HashtrackOpt::clap().gen_completions_to("hashtrack", shell, &mut io::stdout());
+}
+
+fn report_error(context: &mut Context, error: &CliError) {
+    if let CliError::ApiError(api::ApiError::Unauthorized) = error {
+        // The stored token is stale, drop it so the next run starts clean.
+        // HASHTRACK_TOKEN was rejected instead when it is set, and the stored
+        // token may still be fine.
+        if !context.token_from_env() {
+            context.set_token(None).ok();
+        }
+    }
+    print_error(context.json, context.verbose, error);
+}
+
+/// Prints `error` to stderr, as an object with its kind and exit code when
+/// `json` is set.
+fn print_error(json: bool, verbose: u8, error: &CliError) {
+    if json {
+        let mut body = serde_json::json!({
+            "ok": false,
+            "error": error.to_string(),
+            "kind": error.kind().name(),
+            "exitCode": error.exit_code(),
+        });
+        if let CliError::ApiError(api::ApiError::Graphql {
+            code: Some(code), ..
+        }) = error
+        {
+            body["code"] = serde_json::Value::from(code.as_str());
+        }
+        eprintln!("{}", body);
+    } else if verbose > 0 {
+        eprintln!("{:?}", error);
+    } else {
+        eprintln!("{}", error);
+    }
+}
+
+/// Reads commands line by line and runs them with the same context until
+/// `exit` or end of input. Failed commands are reported without leaving.
+async fn run_repl(context: &mut Context) -> Result<(), CliError> {
+    let mut editor = DefaultEditor::new().map_err(|error| CliError::Failure(error.to_string()))?;
+    loop {
+        let line = match editor.readline("hashtrack> ") {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => return Ok(()),
+            Err(error) => return Err(CliError::Failure(error.to_string())),
+        };
+        let words = match shell_words::split(&line) {
+            Ok(words) => words,
+            Err(error) => {
+                eprintln!("{}", error);
+                continue;
+            }
+        };
+        match words.first().map(String::as_str) {
+            None => continue,
+            Some("exit") | Some("quit") => return Ok(()),
+            Some("help") if words.len() == 1 => {
+                HashtrackCommand::clap().print_help().ok();
+                println!();
+                continue;
+            }
+            Some(_) => (),
+        }
+        editor.add_history_entry(line.as_str()).ok();
+        let args = iter::once(String::from("hashtrack")).chain(words);
+        match HashtrackCommand::from_iter_safe(args) {
+            Ok(command) => {
+                if let Err(error) = run_subcommand(context, command).await {
+                    report_error(context, &error);
+                }
+            }
+            Err(error) => println!("{}", error.message),
+        }
+    }
+}
+
+/// Whether `command` talks to the server, as opposed to only dealing with
+/// the local configuration.
+fn needs_endpoint(command: &HashtrackCommand) -> bool {
+    !matches!(
+        command,
+        HashtrackCommand::Config(_)
+            | HashtrackCommand::Profiles
+            | HashtrackCommand::Logout
+            | HashtrackCommand::Completions { .. }
+            | HashtrackCommand::Repl
+    )
+}
+
 async fn run_subcommand(context: &mut Context, opts: HashtrackCommand) -> Result<(), CliError> {
+    if let (Some(error), true) = (context.endpoint_error(), needs_endpoint(&opts)) {
+        return Err(CliError::Usage(error.to_string()));
+    }
     match opts {
         HashtrackCommand::Status => status(context).await,
-        HashtrackCommand::Login => login(context).await,
+        HashtrackCommand::Login {
+            token: Some(token), ..
+        } => login_with_token(context, token).await,
+        HashtrackCommand::Login {
+            email,
+            password,
+            password_stdin,
+            token: None,
+        } => login(context, email, password, password_stdin).await,
         HashtrackCommand::Logout => logout(context),
-        HashtrackCommand::List => get_latest_tweets(context).await,
-        HashtrackCommand::Watch => stream_latest_tweets(context),
-        HashtrackCommand::Tracks => list_tracks(context).await,
-        HashtrackCommand::Track { hashtag } => create_track(context, hashtag).await,
-        HashtrackCommand::Untrack { hashtag } => remove_track(context, hashtag).await,
+        HashtrackCommand::Register => register(context).await,
+        HashtrackCommand::Profiles => list_profiles(context),
+        HashtrackCommand::Ping => ping(context).await,
+        HashtrackCommand::ServerInfo => server_info(context).await,
+        HashtrackCommand::Config(ConfigCommand::Show) => show_config(context),
+        HashtrackCommand::Config(ConfigCommand::Validate) => validate_config(context),
+        HashtrackCommand::Config(ConfigCommand::Get { key }) => get_config(context, key),
+        HashtrackCommand::Config(ConfigCommand::Set { key, value }) => {
+            set_config(context, key, value)
+        }
+        HashtrackCommand::Completions { shell } => {
+            print_completions(shell);
+            Ok(())
+        }
+        HashtrackCommand::Repl => Err(CliError::Usage(String::from("Already in the REPL"))),
+        HashtrackCommand::List(opt) => get_latest_tweets(context, opt).await,
+        HashtrackCommand::Watch(opt) => {
+            hint_if_nothing_tracked(context).await;
+            let seen = tweet::SeenTweets::new(tweet::SEEN_TWEETS_CAPACITY);
+            stream_latest_tweets(context, opt, seen, None).await
+        }
+        HashtrackCommand::Tracks(opt) => list_tracks(context, opt).await,
+        HashtrackCommand::Search(opt) => search_tweets(context, opt).await,
+        HashtrackCommand::Stats { limit } => stats(context, limit).await,
+        HashtrackCommand::Track { hashtags } => {
+            let hashtags = with_stdin_hashtags(hashtags)?;
+            run_track_batch(context, TrackAction::Create, hashtags).await
+        }
+        HashtrackCommand::Untrack {
+            prune: true,
+            older_than,
+            yes,
+            ..
+        } => {
+            let older_than = older_than.unwrap_or_else(|| chrono::Duration::days(30));
+            prune_tracks(context, older_than, yes).await
+        }
+        HashtrackCommand::Untrack {
+            hashtags, all, yes, ..
+        } => remove_tracks(context, hashtags, all, yes).await,
+        HashtrackCommand::Count(command) => count(context, command).await,
     }
 }
 
+/// Whether `--json` or `-j` was passed, for errors found before the
+/// arguments could be parsed.
+fn json_in_args() -> bool {
+    env::args()
+        .skip(1)
+        .take_while(|arg| arg != "--")
+        .any(|arg| {
+            arg == "--json" || (!arg.starts_with("--") && arg.starts_with('-') && arg.contains('j'))
+        })
+}
+
 #[tokio::main]
 async fn main() {
-    let opt: HashtrackOpt = HashtrackOpt::from_args();
-    let mut context = Context::new(opt.config, opt.endpoint).unwrap();
+    let opt: HashtrackOpt = match HashtrackOpt::from_args_safe() {
+        Ok(opt) => opt,
+        Err(error) => match error.kind {
+            ErrorKind::HelpDisplayed | ErrorKind::VersionDisplayed => error.exit(),
+            _ => {
+                let error = CliError::Usage(error.message);
+                print_error(json_in_args(), 0, &error);
+                process::exit(error.exit_code());
+            }
+        },
+    };
+    init_tracing(opt.verbose);
+    if let HashtrackCommand::Completions { shell } = opt.command {
+        print_completions(shell);
+        process::exit(0);
+    }
+    let context =
+        settings_from_opt(&opt).and_then(|settings| Context::new(settings).map_err(CliError::from));
+    let mut context = match context {
+        Ok(context) => context,
+        Err(error) => {
+            print_error(opt.json, opt.verbose, &error);
+            process::exit(error.exit_code());
+        }
+    };
+    if let Some(warning) = context.config_warning() {
+        eprintln!("Warning: {}", warning);
+    }
 
-    match run_subcommand(&mut context, opt.command).await {
-        Ok(_) => (),
+    let result = match opt.command {
+        HashtrackCommand::Repl => run_repl(&mut context).await,
+        command => run_subcommand(&mut context, command).await,
+    };
+    match result {
+        Ok(_) => process::exit(0),
         Err(error) => {
-            println!("{:?}", error);
+            report_error(&mut context, &error);
+            process::exit(error.exit_code());
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn empty_endpoint_is_a_usage_error() {
+        let directory = tempfile::tempdir().unwrap();
+        let config_path = directory.path().join("config.toml");
+        fs::write(&config_path, "[profiles.default]\nendpoint = \"\"\n").unwrap();
+        let context = Context::new(Settings::new(config_path)).unwrap();
+        let error = CliError::from(api::ping(&context).await.unwrap_err());
+        assert!(matches!(
+            error,
+            CliError::ApiError(api::ApiError::NoEndpoint)
+        ));
+        assert_eq!(error.exit_code(), 2);
+        assert_eq!(error.kind().name(), "usage");
+    }
+}
@@ -0,0 +1,25 @@
+use hashtrack::tweet::Tweet;
+use notify_rust::Notification;
+
+/// Shows a desktop notification per tweet. Platforms without notification
+/// support only get a single warning, so streaming carries on regardless.
+#[derive(Default)]
+pub struct Notifier {
+    warned: bool,
+}
+
+impl Notifier {
+    pub fn notify(&mut self, tweet: &Tweet) {
+        let title = match tweet.hashtags().first() {
+            Some(hashtag) => format!("#{}", hashtag),
+            None => tweet.author_name.clone(),
+        };
+        let result = Notification::new().summary(&title).body(&tweet.text).show();
+        if let Err(error) = result {
+            if !self.warned {
+                eprintln!("Could not show desktop notifications: {}", error);
+                self.warned = true;
+            }
+        }
+    }
+}
@@ -17,6 +17,8 @@ pub struct Session {
 
 pub type Creation = create_session::Variables;
 
+/// Logs in, retrying network errors like a query: a retry at worst leaves an
+/// unused session behind, while wrong credentials fail right away.
 pub async fn create(context: &Context, creation: Creation) -> Result<Session, api::ApiError> {
     let data: create_session::ResponseData =
         try_send_query(context, &CreateSession::build_query(creation)).await?;
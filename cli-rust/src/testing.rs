@@ -0,0 +1,66 @@
+//! Helpers shared by the unit tests.
+
+use crate::context::{Context, Settings};
+use serde_json::Value;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+/// Serves GraphQL requests on a local port until the test ends. `respond`
+/// gets how many requests came before and the request body, and returns the
+/// status and body of the response. Returns the endpoint and a count of the
+/// requests served.
+pub fn serve(
+    respond: impl Fn(usize, &Value) -> (u16, String) + Send + 'static,
+) -> (String, Arc<AtomicUsize>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let endpoint = format!("http://{}/graphql", listener.local_addr().unwrap());
+    let served = Arc::new(AtomicUsize::new(0));
+    let counter = served.clone();
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let body = read_body(&stream);
+            let (status, response) = respond(counter.fetch_add(1, Ordering::SeqCst), &body);
+            write!(
+                &stream,
+                "HTTP/1.1 {} Mock\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                response.len(),
+                response
+            )
+            .ok();
+        }
+    });
+    (endpoint, served)
+}
+
+fn read_body(stream: &TcpStream) -> Value {
+    let mut reader = BufReader::new(stream);
+    let mut length = 0;
+    let mut line = String::new();
+    while reader.read_line(&mut line).unwrap_or(0) > 2 {
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                length = value.trim().parse().unwrap_or(0);
+            }
+        }
+        line.clear();
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).ok();
+    serde_json::from_slice(&body).unwrap_or(Value::Null)
+}
+
+/// A logged in context sending its requests to `endpoint`, with its config
+/// in `directory`.
+pub fn context(endpoint: &str, directory: &Path) -> Context {
+    Context::new(Settings {
+        endpoints: vec![endpoint.to_string()],
+        token: Some(String::from("token")),
+        ..Settings::new(directory.join("config.toml"))
+    })
+    .unwrap()
+}
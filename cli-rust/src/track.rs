@@ -1,10 +1,19 @@
 use super::api;
-use super::context::Context;
-use crate::common::try_send_query;
+use super::context::{localconfig, Context};
+use crate::common::{format_relative, paint, try_send_mutation, try_send_query};
+use crate::tweet;
 use ansi_term::Color;
-use chrono::{DateTime, FixedOffset};
+use chrono::{DateTime, Duration, FixedOffset, Utc};
 use graphql_client::GraphQLQuery;
-use std::fmt;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use tracing::warn;
+
+/// The longest hashtag that fits in a 280 character tweet next to its `#`.
+const MAX_HASHTAG_LENGTH: usize = 279;
+/// Cached tracks older than this aren't shown, even offline.
+const MAX_CACHE_AGE_DAYS: i64 = 7;
 
 #[derive(GraphQLQuery)]
 #[graphql(
@@ -30,52 +39,174 @@ struct RemoveTrack;
 pub type Creation = create_track::Variables;
 pub type Removal = remove_track::Variables;
 
+#[derive(Serialize, Deserialize)]
 pub struct Track {
+    #[serde(rename = "hashtagName")]
     pub hashtag_name: String,
+    #[serde(rename = "prettyName")]
     pub pretty_name: String,
+    #[serde(rename = "createdAt")]
     pub created_at: DateTime<FixedOffset>,
 }
 
-impl fmt::Display for Track {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+impl Track {
+    pub fn render(&self, context: &Context) -> String {
+        let color = context.color;
         let hashtag_name = format!("#{}", self.hashtag_name);
         if self.pretty_name == hashtag_name {
-            write!(
-                f,
+            format!(
                 "{} - created at {}",
-                Color::Cyan.bold().paint(hashtag_name),
+                paint(color, Color::Cyan.bold(), &hashtag_name),
                 self.created_at
             )
         } else {
-            write!(
-                f,
+            format!(
                 "{} ({}) - created at {}",
-                Color::Cyan.bold().paint(&self.pretty_name),
+                paint(color, Color::Cyan.bold(), &self.pretty_name),
                 hashtag_name,
                 self.created_at
             )
         }
     }
+
+    /// A line like `render` that also says how long the hashtag has been
+    /// tracked for.
+    pub fn render_verbose(&self, context: &Context) -> String {
+        let since = if context.absolute_time {
+            format!("tracked since {}", self.created_at.to_rfc3339())
+        } else {
+            format!(
+                "tracked since {} ({})",
+                self.created_at.format("%Y-%m-%d"),
+                format_relative(&self.created_at)
+            )
+        };
+        format!(
+            "{}  {}",
+            paint(context.color, Color::Cyan.bold(), &self.pretty_name),
+            paint(context.color, Color::Yellow.normal(), &since)
+        )
+    }
+}
+
+/// Checks that `hashtag` is a single word made of letters, digits and
+/// underscores that fits in a tweet, returning it without the leading `#`.
+pub fn validate(hashtag: &str) -> Result<&str, String> {
+    let name = hashtag.strip_prefix('#').unwrap_or(hashtag);
+    if name.is_empty() {
+        return Err(String::from("Hashtags cannot be empty"));
+    }
+    if name.chars().count() > MAX_HASHTAG_LENGTH {
+        return Err(format!(
+            "Invalid hashtag \"{}\": hashtags are at most {} characters long",
+            hashtag, MAX_HASHTAG_LENGTH
+        ));
+    }
+    match name.chars().find(|c| !c.is_alphanumeric() && *c != '_') {
+        Some(c) if c.is_whitespace() => Err(format!(
+            "Invalid hashtag \"{}\": hashtags cannot contain spaces",
+            hashtag
+        )),
+        Some(c) => Err(format!(
+            "Invalid hashtag \"{}\": '{}' is not allowed, only letters, digits and underscores are",
+            hashtag, c
+        )),
+        None => Ok(name),
+    }
+}
+
+/// Mirrors how the server turns a hashtag into its `hashtag_name`: it is
+/// lowercased and loses `#`, `@`, `|` and whitespace. The server normalizes
+/// on its own as well and stays the source of truth, this only lets the
+/// client compare and send names the way the server will see them. New
+/// tracks are still created from the name as typed, which the server keeps
+/// as the pretty name.
+pub fn normalize(hashtag: &str) -> String {
+    hashtag
+        .to_lowercase()
+        .chars()
+        .filter(|c| !matches!(c, '#' | '|' | '@') && !c.is_whitespace())
+        .collect()
+}
+
+#[derive(Serialize, Deserialize)]
+struct Cache {
+    cached_at: DateTime<FixedOffset>,
+    tracks: Vec<Track>,
 }
 
+/// Fetches every track, keeping a copy on disk for `load_cached`.
 pub async fn get_all(context: &Context) -> Result<Vec<Track>, api::ApiError> {
     let data: tracks::ResponseData =
         try_send_query(context, &Tracks::build_query(tracks::Variables {})).await?;
-    let result = data
-        .tracks
-        .iter()
-        .map(|track| Track {
-            hashtag_name: track.hashtag_name.clone(),
-            pretty_name: track.pretty_name.clone(),
-            created_at: DateTime::parse_from_rfc3339(&track.created_at).unwrap(),
-        })
-        .collect();
-    Ok(result)
+    let cache = Cache {
+        cached_at: Utc::now().into(),
+        tracks: data
+            .tracks
+            .iter()
+            .map(|track| Track {
+                hashtag_name: track.hashtag_name.clone(),
+                pretty_name: track.pretty_name.clone(),
+                created_at: DateTime::parse_from_rfc3339(&track.created_at).unwrap(),
+            })
+            .collect(),
+    };
+    let path = context.tracks_cache_path();
+    if let Err(error) = serde_json::to_vec(&cache)
+        .map_err(io::Error::from)
+        .and_then(|contents| localconfig::write_atomically(&path, &contents))
+    {
+        warn!(%error, path = %path.display(), "could not cache tracks");
+    }
+    Ok(cache.tracks)
+}
+
+/// Forgets the cached tracks after they changed, the next `get_all` caches
+/// them again.
+fn invalidate_cache(context: &Context) {
+    let path = context.tracks_cache_path();
+    match fs::remove_file(&path) {
+        Err(error) if error.kind() != io::ErrorKind::NotFound => {
+            warn!(%error, path = %path.display(), "could not remove the cached tracks");
+        }
+        _ => (),
+    }
+}
+
+/// The tracks from the last successful `get_all` and when they were fetched,
+/// unless they are missing or too old to trust.
+pub fn load_cached(context: &Context) -> Option<(DateTime<FixedOffset>, Vec<Track>)> {
+    let contents = fs::read(context.tracks_cache_path()).ok()?;
+    let cache: Cache = serde_json::from_slice(&contents).ok()?;
+    if Utc::now().signed_duration_since(cache.cached_at) > Duration::days(MAX_CACHE_AGE_DAYS) {
+        return None;
+    }
+    Some((cache.cached_at, cache.tracks))
+}
+
+/// The tracks without any tweet published since `cutoff`, including the ones
+/// without tweets at all.
+pub async fn find_inactive(
+    context: &Context,
+    cutoff: DateTime<Utc>,
+) -> Result<Vec<Track>, api::ApiError> {
+    let mut inactive = Vec::new();
+    for track in get_all(context).await? {
+        // A truncated page still includes the newest tweets, which decide it
+        let tweets = tweet::get_latest_for_track(context, &track.hashtag_name)
+            .await?
+            .tweets;
+        if tweets.iter().all(|tweet| tweet.published_at < cutoff) {
+            inactive.push(track);
+        }
+    }
+    Ok(inactive)
 }
 
 pub async fn create(context: &Context, creation: Creation) -> Result<Track, api::ApiError> {
     let data: create_track::ResponseData =
-        try_send_query(context, &CreateTrack::build_query(creation)).await?;
+        try_send_mutation(context, &CreateTrack::build_query(creation)).await?;
+    invalidate_cache(context);
     Ok(Track {
         hashtag_name: data.create_track.hashtag_name.clone(),
         pretty_name: data.create_track.pretty_name.clone(),
@@ -85,10 +216,128 @@ pub async fn create(context: &Context, creation: Creation) -> Result<Track, api:
 
 pub async fn remove(context: &Context, removal: Removal) -> Result<Track, api::ApiError> {
     let data: remove_track::ResponseData =
-        try_send_query(context, &RemoveTrack::build_query(removal)).await?;
+        try_send_mutation(context, &RemoveTrack::build_query(removal)).await?;
+    invalidate_cache(context);
     Ok(Track {
         hashtag_name: data.remove_track.hashtag_name.clone(),
         pretty_name: data.remove_track.pretty_name.clone(),
         created_at: DateTime::parse_from_rfc3339(&data.remove_track.created_at).unwrap(),
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use serde_json::{json, Value};
+
+    #[test]
+    fn validate_rejects_empty_hashtags() {
+        assert!(validate("").is_err());
+        assert!(validate("#").is_err());
+    }
+
+    #[test]
+    fn validate_rejects_hashtags_longer_than_a_tweet() {
+        assert!(validate(&"a".repeat(MAX_HASHTAG_LENGTH)).is_ok());
+        assert!(validate(&"a".repeat(MAX_HASHTAG_LENGTH + 1)).is_err());
+    }
+
+    #[test]
+    fn validate_rejects_spaces_and_punctuation() {
+        assert!(validate("foo bar")
+            .unwrap_err()
+            .contains("cannot contain spaces"));
+        assert!(validate("foo!").unwrap_err().contains("'!' is not allowed"));
+        assert!(validate("foo-bar").is_err());
+        assert!(validate("##foo").is_err());
+    }
+
+    #[test]
+    fn validate_strips_the_leading_hash() {
+        assert_eq!(validate("#rust"), Ok("rust"));
+    }
+
+    #[test]
+    fn validate_accepts_letters_digits_and_underscores() {
+        assert_eq!(validate("Rust_2018"), Ok("Rust_2018"));
+        assert_eq!(validate("café"), Ok("café"));
+    }
+
+    #[test]
+    fn normalize_folds_case() {
+        assert_eq!(normalize("RustLang"), "rustlang");
+        assert_eq!(normalize("ÇAFÉ"), "çafé");
+    }
+
+    #[test]
+    fn normalize_drops_the_hash_prefix() {
+        assert_eq!(normalize("#rust"), "rust");
+    }
+
+    #[test]
+    fn normalize_trims_surrounding_whitespace() {
+        assert_eq!(normalize("  #Rust\t\n"), "rust");
+    }
+
+    // Cases of `prettyName.toLowerCase().replace(/[#|@|\s]/g, '')` in the
+    // server's hashtag service
+    #[test]
+    fn normalize_matches_the_server() {
+        assert_eq!(normalize("#Rust Lang"), "rustlang");
+        assert_eq!(normalize("@Rust|Lang"), "rustlang");
+        assert_eq!(normalize("##rust#"), "rust");
+        assert_eq!(normalize("rust_lang"), "rust_lang");
+    }
+
+    fn tweet(id: usize, hashtag: &str, published_at: DateTime<Utc>) -> Value {
+        json!({
+            "id": id.to_string(),
+            "authorName": "@author",
+            "text": format!("news #{}", hashtag),
+            "publishedAt": published_at.to_rfc3339(),
+        })
+    }
+
+    #[tokio::test]
+    async fn find_inactive_looks_past_busier_tracks() {
+        let now = Utc::now();
+        let busy: Vec<Value> = (0..tweet::PAGE_SIZE)
+            .map(|id| tweet(id, "busy", now))
+            .collect();
+        let (endpoint, _) = testing::serve(move |_, request| {
+            let data = if request["query"].as_str().unwrap().contains("tracks") {
+                let tracks: Vec<Value> = ["busy", "quiet", "stale"]
+                    .iter()
+                    .map(|name| {
+                        json!({
+                            "hashtagName": name,
+                            "prettyName": format!("#{}", name),
+                            "createdAt": "2026-01-01T00:00:00Z",
+                        })
+                    })
+                    .collect();
+                json!({ "tracks": tracks })
+            } else {
+                // Like the server, an unfiltered query only returns the newest tweets
+                let tweets = match request["variables"]["search"].as_str().unwrap() {
+                    "#quiet" => vec![tweet(100, "quiet", now - Duration::days(2))],
+                    "#stale" => vec![tweet(101, "stale", now - Duration::days(60))],
+                    _ => busy.clone(),
+                };
+                json!({ "tweets": tweets })
+            };
+            (200, json!({ "data": data }).to_string())
+        });
+        let directory = tempfile::tempdir().unwrap();
+        let context = testing::context(&endpoint, directory.path());
+        let inactive = find_inactive(&context, now - Duration::days(30))
+            .await
+            .unwrap();
+        let names: Vec<&str> = inactive
+            .iter()
+            .map(|track| track.hashtag_name.as_str())
+            .collect();
+        assert_eq!(names, ["stale"]);
+    }
+}
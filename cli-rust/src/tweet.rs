@@ -1,27 +1,34 @@
 use super::api;
 use super::context::Context;
-use crate::common::try_send_query;
+use crate::common::{format_relative, paint, try_send_query};
+use crate::track;
 use ansi_term::Color;
 use api::ws;
 use api::ws::WsMessage;
 use chrono::{DateTime, FixedOffset};
 use graphql_client::GraphQLQuery;
 use graphql_client::Response;
+use regex::Regex;
 use serde::{Deserialize, Serialize};
-use std::fmt;
-use std::sync::mpsc;
-use std::sync::mpsc::{Receiver, Sender};
+use std::borrow::Cow;
+use std::collections::{HashSet, VecDeque};
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
 use std::thread;
 use textwrap::Wrapper;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use unicode_segmentation::UnicodeSegmentation;
+use websocket::sync::Writer;
 use websocket::OwnedMessage;
 
-const WRAPPER: Wrapper<'_, textwrap::NoHyphenation> = Wrapper {
-    splitter: textwrap::NoHyphenation,
-    initial_indent: "    ",
-    subsequent_indent: "    ",
-    break_words: false,
-    width: 60,
-};
+const TEXT_INDENT: &str = "    ";
+
+/// The server returns at most this many tweets per query, the newest ones.
+pub const PAGE_SIZE: usize = 50;
+
+/// How many tweet IDs are remembered to filter out duplicates in streams.
+pub const SEEN_TWEETS_CAPACITY: usize = 500;
 
 #[derive(GraphQLQuery)]
 #[graphql(
@@ -47,22 +54,278 @@ pub struct Tweet {
     pub published_at: DateTime<FixedOffset>,
 }
 
-impl fmt::Display for Tweet {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let url = format!(
-            "https://twitter.com/{}/status/{}",
-            self.author_name.replace("@", ""),
-            self.id
+impl Tweet {
+    /// Links to the tweet, using Twitter's author-less URL when the API
+    /// didn't return an author.
+    pub fn url(&self) -> String {
+        let author = self.author_name.replace("@", "");
+        if author.is_empty() {
+            format!("https://twitter.com/i/web/status/{}", self.id)
+        } else {
+            format!("https://twitter.com/{}/status/{}", author, self.id)
+        }
+    }
+
+    /// Tweet IDs are increasing integers, but too big to be compared safely
+    /// by every client, so they are compared by length and then digits.
+    pub fn is_newer_than(&self, id: &str) -> bool {
+        (self.id.len(), self.id.as_str()) > (id.len(), id)
+    }
+
+    /// The hashtags mentioned in the text, without the leading `#`.
+    pub fn hashtags(&self) -> Vec<&str> {
+        self.text
+            .split_whitespace()
+            .filter_map(|word| word.strip_prefix('#'))
+            .map(|name| name.trim_end_matches(|c: char| !c.is_alphanumeric() && c != '_'))
+            .filter(|name| !name.is_empty())
+            .collect()
+    }
+
+    /// Whether the text mentions the tracked hashtag `name`, comparing them
+    /// with `track::normalize`.
+    pub fn mentions(&self, name: &str) -> bool {
+        self.hashtags()
+            .iter()
+            .any(|hashtag| track::normalize(hashtag) == name)
+    }
+
+    /// Whether the text matches every one of `patterns`.
+    pub fn matches_all(&self, patterns: &[Regex]) -> bool {
+        patterns.iter().all(|pattern| pattern.is_match(&self.text))
+    }
+
+    fn format_published_at(&self, context: &Context) -> String {
+        if context.absolute_time {
+            self.published_at.to_rfc3339()
+        } else {
+            format_relative(&self.published_at)
+        }
+    }
+
+    pub fn render(&self, context: &Context) -> String {
+        if context.compact {
+            return self.render_compact(context);
+        }
+        if let Some(template) = &context.format {
+            return self.render_template(template, context);
+        }
+        let color = context.color;
+        let published_at = paint(
+            color,
+            Color::Yellow.normal(),
+            &self.format_published_at(context),
         );
-        write!(
-            f,
-            "{} - {}\n{}\n{}\n",
-            Color::Cyan.bold().paint(&self.author_name),
-            &self.published_at,
-            WRAPPER.fill(&self.text),
-            Color::RGB(80, 100, 100).bold().paint(&url),
+        let author_name = for_display(context, &self.author_name);
+        let header = if author_name.trim().is_empty() {
+            published_at
+        } else {
+            format!(
+                "{} - {}",
+                paint(color, Color::Cyan.bold(), &author_name),
+                published_at
+            )
+        };
+        let text = for_display(context, &self.text);
+        let url = self.url();
+        format!(
+            "{}\n{}\n{}\n",
+            header,
+            highlight_hashtags(&wrap_text(&text, context.wrap), color),
+            paint(
+                color,
+                Color::RGB(80, 100, 100).bold(),
+                &for_display(context, &url)
+            ),
+        )
+    }
+
+    /// The first hashtag, time, author and text on one line, with the text cut
+    /// short so the line fits in `context.wrap` columns.
+    fn render_compact(&self, context: &Context) -> String {
+        let hashtag = self
+            .hashtags()
+            .first()
+            .map(|hashtag| format!("#{} ", for_display(context, hashtag)))
+            .unwrap_or_default();
+        let published_at = self.format_published_at(context);
+        let author_name = for_display(context, &self.author_name);
+        let author = if author_name.trim().is_empty() {
+            String::new()
+        } else {
+            format!(" {}", author_name)
+        };
+        let prefix_width = [hashtag.as_str(), &published_at, &author, ": "]
+            .iter()
+            .map(|part| part.graphemes(true).count())
+            .sum::<usize>();
+        let text = for_display(context, &self.text).replace(['\n', '\r'], " ");
+        let text = match context.wrap {
+            0 => text,
+            width => truncate(&text, width.saturating_sub(prefix_width)),
+        };
+        format!(
+            "{}{}{}: {}",
+            paint(context.color, Color::Cyan.bold(), &hashtag),
+            paint(context.color, Color::Yellow.normal(), &published_at),
+            author,
+            text
+        )
+    }
+
+    /// A single uncolored line with the publication time, for log files.
+    pub fn log_line(&self) -> String {
+        format!(
+            "[{}] {}: {} {}",
+            self.published_at.to_rfc3339(),
+            self.author_name,
+            self.text.replace('\n', " "),
+            self.url()
         )
     }
+
+    /// Replaces the known `{placeholder}`s of `template` with the tweet fields,
+    /// leaving anything else untouched.
+    pub fn render_template(&self, template: &str, context: &Context) -> String {
+        let mut result = String::with_capacity(template.len());
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            result.push_str(&rest[..start]);
+            rest = &rest[start..];
+            let end = match rest.find('}') {
+                Some(end) => end,
+                None => break,
+            };
+            let value = match &rest[1..end] {
+                "id" => for_display(context, &self.id).into_owned(),
+                "text" => for_display(context, &self.text).into_owned(),
+                "hashtag" => for_display(context, self.hashtags().first().copied().unwrap_or(""))
+                    .into_owned(),
+                "author" => for_display(context, &self.author_name).into_owned(),
+                "created_at" => self.format_published_at(context),
+                "url" => for_display(context, &self.url()).into_owned(),
+                _ => rest[..=end].to_string(),
+            };
+            result.push_str(&value);
+            rest = &rest[end + 1..];
+        }
+        result.push_str(rest);
+        result
+    }
+}
+
+/// `text` made safe to print with `--plain`, since every tweet field comes
+/// from Twitter users.
+fn for_display<'a>(context: &Context, text: &'a str) -> Cow<'a, str> {
+    if context.plain {
+        Cow::Owned(sanitize(text))
+    } else {
+        Cow::Borrowed(text)
+    }
+}
+
+/// Cuts `text` to at most `width` graphemes, ending it with an ellipsis when
+/// anything was left out.
+fn truncate(text: &str, width: usize) -> String {
+    if text.graphemes(true).count() <= width {
+        return text.to_string();
+    }
+    let mut result: String = text.graphemes(true).take(width.saturating_sub(1)).collect();
+    result.push('…');
+    result
+}
+
+/// Indents `text` and wraps it at `width` columns, or only indents it when
+/// `width` is 0.
+fn wrap_text(text: &str, width: usize) -> String {
+    let width = if width == 0 { usize::MAX } else { width };
+    Wrapper::with_splitter(width, textwrap::NoHyphenation)
+        .initial_indent(TEXT_INDENT)
+        .subsequent_indent(TEXT_INDENT)
+        .break_words(false)
+        .fill(text)
+}
+
+/// Makes untrusted text safe to print on a terminal: ANSI escape sequences,
+/// zero-width characters and emoji are dropped, and any other control
+/// character becomes a space.
+pub fn sanitize(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c == '\u{1b}' {
+            // CSI sequences end with a byte in '@'..='~', other escapes are
+            // a single character long
+            if chars.next() == Some('[') {
+                chars.find(|c| ('@'..='~').contains(c));
+            }
+        } else if c.is_control() {
+            result.push(' ');
+        } else if !is_invisible_or_emoji(c) {
+            result.push(c);
+        }
+    }
+    result
+}
+
+fn is_invisible_or_emoji(c: char) -> bool {
+    matches!(c,
+        '\u{200B}'..='\u{200F}'
+        | '\u{202A}'..='\u{202E}'
+        | '\u{2060}'..='\u{2064}'
+        | '\u{FE00}'..='\u{FE0F}'
+        | '\u{FEFF}'
+        | '\u{2600}'..='\u{27BF}'
+        | '\u{1F000}'..='\u{1FAFF}'
+    )
+}
+
+/// A bounded set of the most recently seen tweet IDs, which forgets the
+/// oldest ones once full.
+pub struct SeenTweets {
+    ids: HashSet<String>,
+    order: VecDeque<String>,
+    capacity: usize,
+}
+
+impl SeenTweets {
+    pub fn new(capacity: usize) -> Self {
+        SeenTweets {
+            ids: HashSet::with_capacity(capacity),
+            order: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// Records `id`, returning whether it hadn't been seen before.
+    pub fn insert(&mut self, id: &str) -> bool {
+        if self.ids.contains(id) {
+            return false;
+        }
+        if self.order.len() == self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+        self.ids.insert(id.to_string());
+        self.order.push_back(id.to_string());
+        true
+    }
+}
+
+fn highlight_hashtags(text: &str, color: bool) -> String {
+    text.split('\n')
+        .map(|line| {
+            line.split(' ')
+                .map(|word| match word.strip_prefix('#') {
+                    Some(name) if !name.is_empty() => paint(color, Color::Green.bold(), word),
+                    _ => word.to_string(),
+                })
+                .collect::<Vec<_>>()
+                .join(" ")
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
 }
 
 pub async fn get_latest(context: &Context, search: String) -> Result<Vec<Tweet>, api::ApiError> {
@@ -82,46 +345,220 @@ pub async fn get_latest(context: &Context, search: String) -> Result<Vec<Tweet>,
     Ok(result)
 }
 
-pub fn stream_latest(context: &Context, search: String) -> Receiver<Tweet> {
-    let (tx, rx): (Sender<Tweet>, Receiver<Tweet>) = mpsc::channel();
-    let init_connection = ws::get_connection_init_message(context);
-    let endpoint = context.endpoint.clone();
-    thread::spawn(move || {
-        let mut client = ws::build_client(endpoint);
-        client
-            .send_message(&OwnedMessage::Binary(init_connection))
-            .unwrap();
-        let first_message = match client.recv_message().unwrap() {
-            OwnedMessage::Text(data) => serde_json::from_str::<WsMessage<String>>(&data).unwrap(),
-            _ => return,
+/// The latest tweets of a tracked hashtag.
+pub struct TrackTweets {
+    /// Oldest first.
+    pub tweets: Vec<Tweet>,
+    /// Whether the server returned a full page, so older tweets were left out.
+    pub truncated: bool,
+}
+
+/// The latest tweets mentioning the tracked hashtag `name`. Searching for it
+/// on its own keeps busier hashtags from filling up the page.
+pub async fn get_latest_for_track(
+    context: &Context,
+    name: &str,
+) -> Result<TrackTweets, api::ApiError> {
+    let mut tweets = get_latest(context, format!("#{}", name)).await?;
+    // Counted before dropping the tweets that only matched as a substring
+    let truncated = tweets.len() >= PAGE_SIZE;
+    tweets.retain(|tweet| tweet.mentions(name));
+    Ok(TrackTweets { tweets, truncated })
+}
+
+/// What a tweet subscription yields besides errors.
+pub enum StreamEvent {
+    Tweet(Tweet),
+    KeepAlive,
+}
+
+/// The writing half of a subscription's connection, shared with its
+/// `TweetStream` so the connection can be closed while a background thread
+/// is blocked reading from it.
+#[derive(Default)]
+struct Connection {
+    closed: bool,
+    writer: Option<Writer<TcpStream>>,
+}
+
+impl Connection {
+    /// Tells the server to stop the subscription and shuts the socket down,
+    /// which ends the read the thread is blocked in.
+    fn close(&mut self) {
+        self.closed = true;
+        if let Some(mut writer) = self.writer.take() {
+            writer.send_message(&ws::build_stop_message()).ok();
+            writer.send_message(&ws::build_terminate_message()).ok();
+            writer.send_message(&OwnedMessage::Close(None)).ok();
+            writer.shutdown_all().ok();
+        }
+    }
+}
+
+fn send(connection: &Mutex<Connection>, message: &OwnedMessage) -> Option<()> {
+    connection
+        .lock()
+        .ok()?
+        .writer
+        .as_mut()?
+        .send_message(message)
+        .ok()
+}
+
+fn subscribe(
+    endpoint: String,
+    init_connection: Vec<u8>,
+    search: String,
+    tx: UnboundedSender<Result<StreamEvent, api::ApiError>>,
+    connection: Arc<Mutex<Connection>>,
+) -> Option<()> {
+    let (mut reader, writer) = ws::build_client(endpoint).ok()?.split().ok()?;
+    {
+        let mut connection = connection.lock().ok()?;
+        if connection.closed {
+            writer.shutdown_all().ok();
+            return None;
+        }
+        connection.writer = Some(writer);
+    }
+    send(&connection, &OwnedMessage::Binary(init_connection))?;
+    let first_message = match reader.recv_message().ok()? {
+        OwnedMessage::Text(data) => serde_json::from_str::<WsMessage<String>>(&data).ok()?,
+        _ => return None,
+    };
+    if !ws::is_ack_message(first_message) {
+        return None;
+    }
+    let start_message =
+        ws::build_start_message(NewTweet::build_query(new_tweet::Variables { search }));
+    send(&connection, &start_message)?;
+    loop {
+        let message = match reader.recv_message().ok()? {
+            OwnedMessage::Text(data) => {
+                serde_json::from_str::<WsMessage<Response<new_tweet::ResponseData>>>(&data).ok()?
+            }
+            OwnedMessage::Binary(data) => {
+                serde_json::from_slice::<WsMessage<Response<new_tweet::ResponseData>>>(&data)
+                    .ok()?
+            }
+            _ => return None,
         };
-        if !ws::is_ack_message(first_message) {
-            return;
+        if ws::is_keep_alive_message(&message) {
+            tx.send(Ok(StreamEvent::KeepAlive)).ok()?;
+            continue;
         }
-        let start_message =
-            ws::build_start_message(NewTweet::build_query(new_tweet::Variables { search }));
-        client.send_message(&start_message).unwrap();
-        loop {
-            let message = match client.recv_message().unwrap() {
-                OwnedMessage::Text(data) => {
-                    serde_json::from_str::<WsMessage<Response<new_tweet::ResponseData>>>(&data)
-                        .unwrap()
-                }
-                OwnedMessage::Binary(data) => {
-                    serde_json::from_slice::<WsMessage<Response<new_tweet::ResponseData>>>(&data)
-                        .unwrap()
+        let payload = match message.payload {
+            Some(payload) => payload,
+            None => continue,
+        };
+        let tweet = match payload.data {
+            Some(data) => data.new_tweet,
+            None => {
+                // An expired token only shows up in the errors of new events
+                if let api::ApiError::Unauthorized = api::ApiError::from_graphql(payload.errors) {
+                    tx.send(Err(api::ApiError::Unauthorized)).ok()?;
+                    return None;
                 }
-                _ => return,
-            };
-            let tweet = message.payload.unwrap().data.unwrap().new_tweet;
-            tx.send(Tweet {
-                id: tweet.id,
-                text: tweet.text,
-                author_name: tweet.author_name,
-                published_at: DateTime::parse_from_rfc3339(&tweet.published_at).unwrap(),
-            })
-            .unwrap();
+                continue;
+            }
+        };
+        tx.send(Ok(StreamEvent::Tweet(Tweet {
+            id: tweet.id,
+            text: tweet.text,
+            author_name: tweet.author_name,
+            published_at: DateTime::parse_from_rfc3339(&tweet.published_at).ok()?,
+        })))
+        .ok()?;
+    }
+}
+
+/// New tweets from a subscription running on a background thread. Closing or
+/// dropping it stops the subscription on the server and ends the thread.
+pub struct TweetStream {
+    receiver: UnboundedReceiver<Result<StreamEvent, api::ApiError>>,
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl TweetStream {
+    /// The next event, or `None` as soon as the subscription dropped, so
+    /// callers can detect it and reconnect. That happens right after an
+    /// `Unauthorized` error if the server stopped accepting the token.
+    pub async fn recv(&mut self) -> Option<Result<StreamEvent, api::ApiError>> {
+        self.receiver.recv().await
+    }
+
+    pub fn close(&self) {
+        if let Ok(mut connection) = self.connection.lock() {
+            connection.close();
+        }
+    }
+}
+
+impl Drop for TweetStream {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+/// Subscribes to new tweets matching `search`.
+pub fn stream_latest(context: &Context, search: String) -> TweetStream {
+    let (tx, receiver) = mpsc::unbounded_channel();
+    let connection = Arc::new(Mutex::new(Connection::default()));
+    let init_connection = ws::get_connection_init_message(context);
+    let endpoint = context.endpoint().to_string();
+    let shared = connection.clone();
+    thread::spawn(move || subscribe(endpoint, init_connection, search, tx, shared));
+    TweetStream {
+        receiver,
+        connection,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use serde_json::json;
+
+    fn tweet(text: &str) -> Tweet {
+        Tweet {
+            id: String::from("1"),
+            author_name: String::from("@author"),
+            text: String::from(text),
+            published_at: DateTime::parse_from_rfc3339("2026-10-01T00:00:00Z").unwrap(),
         }
-    });
-    return rx;
+    }
+
+    #[test]
+    fn mentions_ignores_longer_hashtags() {
+        assert!(!tweet("learning #rustlang today").mentions("rust"));
+    }
+
+    #[test]
+    fn mentions_ignores_case_and_punctuation() {
+        assert!(tweet("news about #Rust, again").mentions("rust"));
+    }
+
+    #[tokio::test]
+    async fn full_pages_stay_truncated_after_dropping_substring_matches() {
+        let (endpoint, _) = testing::serve(|_, _| {
+            let tweets: Vec<_> = (0..PAGE_SIZE)
+                .map(|id| {
+                    let hashtag = if id < 3 { "rustlang" } else { "rust" };
+                    json!({
+                        "id": id.to_string(),
+                        "authorName": "@author",
+                        "text": format!("news #{}", hashtag),
+                        "publishedAt": "2026-10-01T00:00:00Z",
+                    })
+                })
+                .collect();
+            (200, json!({ "data": { "tweets": tweets } }).to_string())
+        });
+        let directory = tempfile::tempdir().unwrap();
+        let context = testing::context(&endpoint, directory.path());
+        let page = get_latest_for_track(&context, "rust").await.unwrap();
+        assert_eq!(page.tweets.len(), PAGE_SIZE - 3);
+        assert!(page.truncated);
+    }
 }
@@ -1,7 +1,8 @@
 use super::api;
 use super::context::Context;
-use crate::common::try_send_query;
+use crate::common::{try_send_mutation, try_send_query};
 use graphql_client::GraphQLQuery;
+use serde::Serialize;
 
 #[derive(GraphQLQuery)]
 #[graphql(
@@ -10,7 +11,16 @@ use graphql_client::GraphQLQuery;
 )]
 struct CurrentUser;
 
-#[derive(Debug)]
+#[derive(GraphQLQuery)]
+#[graphql(
+    schema_path = "graphql/schema.graphql",
+    query_path = "graphql/createUser.graphql"
+)]
+struct CreateUser;
+
+pub type Creation = create_user::Variables;
+
+#[derive(Serialize, Debug)]
 pub struct User {
     pub id: String,
     pub name: String,
@@ -29,3 +39,13 @@ pub async fn get_current(context: &Context) -> Result<User, api::ApiError> {
         email: data.current_user.email,
     })
 }
+
+pub async fn create(context: &Context, creation: Creation) -> Result<User, api::ApiError> {
+    let data: create_user::ResponseData =
+        try_send_mutation(context, &CreateUser::build_query(creation)).await?;
+    Ok(User {
+        id: data.create_user.id,
+        name: data.create_user.name,
+        email: data.create_user.email,
+    })
+}